@@ -1,5 +1,16 @@
+use failure::Fail;
+
 pub use failure::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub use failure::err_msg;
+
+/// A request parameter that is malformed or cannot be honoured
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid parameter: {}", _0)]
+pub struct InvalidParameter(pub String);
+
+pub fn invalid_param<S: Into<String>>(msg: S) -> Error {
+    Error::from(InvalidParameter(msg.into()))
+}
@@ -0,0 +1,366 @@
+use super::Rgba16Image;
+use image::{imageops, DynamicImage, FilterType, ImageBuffer, RgbImage, Rgba};
+use lcms2::{ColorSpaceSignature, Intent, PixelFormat, Profile, Transform};
+use libcore::errors::*;
+
+/// Convert the decoded original from its embedded profile into the target profile.
+/// Returns the converted image together with the ICC data to tag the output with, the
+/// embedded profile for the colour spaces that are not converted.
+pub(crate) fn convert(
+    data: &[u8],
+    img: DynamicImage,
+    source_icc: Option<&[u8]>,
+    target_path: Option<&str>,
+) -> Result<(DynamicImage, Vec<u8>)> {
+    let target = if let Some(path) = target_path {
+        Profile::new_file(path)?
+    } else {
+        Profile::new_srgb()
+    };
+    let target_icc = target.icc()?;
+    let source = if let Some(icc) = source_icc {
+        Profile::new_icc(icc)?
+    } else {
+        // Untagged images are assumed to be sRGB already
+        return Ok((img, target_icc));
+    };
+    let img = match source.color_space() {
+        ColorSpaceSignature::CmykData => convert_cmyk(data, &source, &target)?,
+        ColorSpaceSignature::RgbData => convert_rgb(img, &source, &target)?,
+        ColorSpaceSignature::GrayData => convert_gray(img, &source, &target)?,
+        // Exotic profiles are left as decoded, still described by their profile
+        _ => return Ok((img, source.icc()?)),
+    };
+    Ok((img, target_icc))
+}
+
+/// CMYK profiles are applied by `convert` whatever the policy, the `image` crate decodes CMYK
+/// images without them
+pub(crate) fn is_cmyk(icc: &[u8]) -> bool {
+    match Profile::new_icc(icc).map(|profile| profile.color_space()) {
+        Ok(ColorSpaceSignature::CmykData) => true,
+        _ => false,
+    }
+}
+
+/// Whether the colour space of a profile is the one of the pixels of `img`
+pub(crate) fn describes(icc: &[u8], img: &DynamicImage) -> bool {
+    let gray = match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => true,
+        _ => false,
+    };
+    match Profile::new_icc(icc).map(|profile| profile.color_space()) {
+        Ok(ColorSpaceSignature::GrayData) => gray,
+        Ok(ColorSpaceSignature::RgbData) => !gray,
+        _ => false,
+    }
+}
+
+fn convert_rgb(img: DynamicImage, source: &Profile, target: &Profile) -> Result<DynamicImage> {
+    let transform: Transform<[u8; 3], [u8; 3]> = Transform::new(
+        source,
+        PixelFormat::RGB_8,
+        target,
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )?;
+    let rgba = img.to_rgba();
+    let (width, height) = rgba.dimensions();
+    let src: Vec<[u8; 3]> = rgba
+        .pixels()
+        .map(|p| [p.data[0], p.data[1], p.data[2]])
+        .collect();
+    let mut dst = vec![[0u8; 3]; src.len()];
+    transform.transform_pixels(&src, &mut dst);
+    let has_alpha = match img {
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+        _ => false,
+    };
+    if has_alpha {
+        let converted = ImageBuffer::from_fn(width, height, |x, y| {
+            let rgb = dst[(y * width + x) as usize];
+            let alpha = rgba.get_pixel(x, y).data[3];
+            image::Rgba {
+                data: [rgb[0], rgb[1], rgb[2], alpha],
+            }
+        });
+        Ok(DynamicImage::ImageRgba8(converted))
+    } else {
+        let raw = dst.iter().flat_map(|p| p.iter()).cloned().collect();
+        let converted: RgbImage = ImageBuffer::from_raw(width, height, raw)
+            .ok_or(err_msg("Converted buffer does not match the image size"))?;
+        Ok(DynamicImage::ImageRgb8(converted))
+    }
+}
+
+/// Grayscale originals become RGB in the target profile
+fn convert_gray(img: DynamicImage, source: &Profile, target: &Profile) -> Result<DynamicImage> {
+    let transform: Transform<u8, [u8; 3]> = Transform::new(
+        source,
+        PixelFormat::GRAY_8,
+        target,
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )?;
+    let luma = img.to_luma_alpha();
+    let (width, height) = luma.dimensions();
+    let src: Vec<u8> = luma.pixels().map(|p| p.data[0]).collect();
+    let mut dst = vec![[0u8; 3]; src.len()];
+    transform.transform_pixels(&src, &mut dst);
+    match img {
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => {
+            let converted = ImageBuffer::from_fn(width, height, |x, y| {
+                let rgb = dst[(y * width + x) as usize];
+                let alpha = luma.get_pixel(x, y).data[1];
+                image::Rgba {
+                    data: [rgb[0], rgb[1], rgb[2], alpha],
+                }
+            });
+            Ok(DynamicImage::ImageRgba8(converted))
+        }
+        _ => {
+            let raw = dst.iter().flat_map(|p| p.iter()).cloned().collect();
+            let converted: RgbImage = ImageBuffer::from_raw(width, height, raw)
+                .ok_or(err_msg("Converted buffer does not match the image size"))?;
+            Ok(DynamicImage::ImageRgb8(converted))
+        }
+    }
+}
+
+/// CMYK JPEGs are decoded without the naive conversion of the `image` crate
+fn convert_cmyk(data: &[u8], source: &Profile, target: &Profile) -> Result<DynamicImage> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let pixels = decoder.decode()?;
+    let info = decoder
+        .info()
+        .ok_or(err_msg("Missing JPEG image information"))?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Err(err_msg("CMYK profile embedded in a non CMYK image"));
+    }
+    let transform: Transform<[u8; 4], [u8; 3]> = Transform::new(
+        source,
+        PixelFormat::CMYK_8,
+        target,
+        PixelFormat::RGB_8,
+        Intent::Perceptual,
+    )?;
+    let src: Vec<[u8; 4]> = pixels.chunks(4).map(|p| [p[0], p[1], p[2], p[3]]).collect();
+    let mut dst = vec![[0u8; 3]; src.len()];
+    transform.transform_pixels(&src, &mut dst);
+    let raw = dst.iter().flat_map(|p| p.iter()).cloned().collect();
+    let converted: RgbImage =
+        ImageBuffer::from_raw(u32::from(info.width), u32::from(info.height), raw)
+            .ok_or(err_msg("Converted buffer does not match the image size"))?;
+    Ok(DynamicImage::ImageRgb8(converted))
+}
+
+/// The largest dimensions within `width` x `height` that keep the aspect ratio,
+/// as `DynamicImage::resize` computes them
+pub(crate) fn fit_dimensions(dimensions: (u32, u32), width: u32, height: u32) -> (u32, u32) {
+    let (w, h) = dimensions;
+    let ratio = f64::min(
+        f64::from(width) / f64::from(w),
+        f64::from(height) / f64::from(h),
+    );
+    let nw = ((f64::from(w) * ratio).round() as u32).max(1);
+    let nh = ((f64::from(h) * ratio).round() as u32).max(1);
+    (nw, nh)
+}
+
+/// Resample in linear light, decoding sRGB to 16-bit linear values first
+pub(crate) fn resize_linear(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter_type: FilterType,
+) -> DynamicImage {
+    let to_linear: Vec<u16> = (0..256)
+        .map(|v| (srgb_to_linear(v as f32 / 255.0) * 65535.0).round() as u16)
+        .collect();
+    let to_srgb: Vec<u8> = (0..65536)
+        .map(|v| (linear_to_srgb(v as f32 / 65535.0) * 255.0).round() as u8)
+        .collect();
+    let rgba = img.to_rgba();
+    let linear: ImageBuffer<Rgba<u16>, Vec<u16>> =
+        ImageBuffer::from_fn(rgba.width(), rgba.height(), |x, y| {
+            let p = rgba.get_pixel(x, y).data;
+            Rgba {
+                data: [
+                    to_linear[p[0] as usize],
+                    to_linear[p[1] as usize],
+                    to_linear[p[2] as usize],
+                    u16::from(p[3]) * 257,
+                ],
+            }
+        });
+    let resized = imageops::resize(&linear, width, height, filter_type);
+    let encoded = ImageBuffer::from_fn(width, height, |x, y| {
+        let p = resized.get_pixel(x, y).data;
+        Rgba {
+            data: [
+                to_srgb[p[0] as usize],
+                to_srgb[p[1] as usize],
+                to_srgb[p[2] as usize],
+                (p[3] / 257) as u8,
+            ],
+        }
+    });
+    let result = DynamicImage::ImageRgba8(encoded);
+    match img {
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => result,
+        _ => DynamicImage::ImageRgb8(result.to_rgb()),
+    }
+}
+
+/// `resize_linear` for 16-bit images, resampled as floating point linear values
+pub(crate) fn resize_linear_deep(
+    img: &Rgba16Image,
+    width: u32,
+    height: u32,
+    filter_type: FilterType,
+) -> Rgba16Image {
+    let to_linear: Vec<f32> = (0..65536)
+        .map(|v| srgb_to_linear(v as f32 / 65535.0))
+        .collect();
+    let linear: ImageBuffer<Rgba<f32>, Vec<f32>> =
+        ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+            let p = img.get_pixel(x, y).data;
+            Rgba {
+                data: [
+                    to_linear[p[0] as usize],
+                    to_linear[p[1] as usize],
+                    to_linear[p[2] as usize],
+                    f32::from(p[3]) / 65535.0,
+                ],
+            }
+        });
+    let resized = imageops::resize(&linear, width, height, filter_type);
+    let encode = |v: f32| (v.max(0.0).min(1.0) * 65535.0).round() as u16;
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let p = resized.get_pixel(x, y).data;
+        Rgba {
+            data: [
+                encode(linear_to_srgb(p[0].max(0.0))),
+                encode(linear_to_srgb(p[1].max(0.0))),
+                encode(linear_to_srgb(p[2].max(0.0))),
+                encode(p[3]),
+            ],
+        }
+    })
+}
+
+pub(crate) fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub(crate) fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert_rgb() {
+        let srgb = Profile::new_srgb();
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgb {
+                data: [200, 100, 50],
+            },
+        ));
+        let converted = convert_rgb(img, &srgb, &srgb).unwrap();
+        let pixel = converted.to_rgb().get_pixel(0, 0).data;
+        assert!((i32::from(pixel[0]) - 200).abs() <= 1);
+    }
+
+    #[test]
+    fn test_convert_cmyk() {
+        // No ink on the left half, full black ink on the right half
+        let data = std::fs::read("../../originals/cmyk.jpg").unwrap();
+        let icc = crate::metadata::Metadata::read(&data).unwrap().icc;
+        let img = image::load_from_memory(&data).unwrap();
+        let (converted, target_icc) =
+            convert(&data, img, icc.as_ref().map(|i| i.as_slice()), None).unwrap();
+        let rgb = converted.to_rgb();
+        assert!(rgb.get_pixel(0, 0).data.iter().all(|v| *v >= 250));
+        assert!(rgb.get_pixel(15, 0).data.iter().all(|v| *v <= 5));
+        assert_eq!(target_icc, Profile::new_srgb().icc().unwrap());
+    }
+
+    #[test]
+    fn test_resize_cmyk() {
+        // Converted with the default policy too, and tagged with the profile of the pixels
+        let opts =
+            crate::ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let img_info = crate::ImageInfo::new("cmyk", "jpg", None, None);
+        let hash = crate::resize(&opts, &img_info).unwrap();
+        let data = std::fs::read(format!("../../outputs/{}.jpg", hash)).unwrap();
+        let icc = crate::metadata::Metadata::read(&data).unwrap().icc;
+        assert_eq!(icc, Some(Profile::new_srgb().icc().unwrap()));
+        let rgb = image::load_from_memory(&data).unwrap().to_rgb();
+        assert!(rgb.get_pixel(0, 0).data.iter().all(|v| *v >= 240));
+        assert!(rgb.get_pixel(15, 0).data.iter().all(|v| *v <= 15));
+    }
+
+    #[test]
+    fn test_convert_gray() {
+        let white_point = lcms2::CIExyY {
+            x: 0.3457,
+            y: 0.3585,
+            Y: 1.0,
+        };
+        let gray = Profile::new_gray(&white_point, &lcms2::ToneCurve::new(2.2)).unwrap();
+        let img =
+            DynamicImage::ImageLuma8(ImageBuffer::from_pixel(2, 2, image::Luma { data: [255] }));
+        let (converted, target_icc) = convert(&[], img, Some(&gray.icc().unwrap()), None).unwrap();
+        assert!(converted
+            .to_rgb()
+            .get_pixel(0, 0)
+            .data
+            .iter()
+            .all(|v| *v >= 250));
+        assert_eq!(target_icc, Profile::new_srgb().icc().unwrap());
+        // Not converted, still tagged with its own profile
+        let lab = Profile::new_lab4_context(lcms2::GlobalContext::new(), &white_point).unwrap();
+        let img = DynamicImage::ImageRgb8(ImageBuffer::new(2, 2));
+        let (_, icc) = convert(&[], img, Some(&lab.icc().unwrap()), None).unwrap();
+        assert_eq!(icc, lab.icc().unwrap());
+    }
+
+    #[test]
+    fn test_resize_linear() {
+        // Alternating black and white columns average to mid grey in linear light
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, _| {
+            let v = if x % 2 == 0 { 0 } else { 255 };
+            image::Rgb { data: [v, v, v] }
+        }));
+        let resized = resize_linear(&img, 8, 8, FilterType::Triangle);
+        let pixel = resized.to_rgb().get_pixel(4, 4).data;
+        assert!((i32::from(pixel[0]) - 188).abs() <= 2);
+    }
+
+    #[test]
+    fn test_resize_linear_deep() {
+        let img: Rgba16Image = ImageBuffer::from_fn(64, 64, |x, _| {
+            let v = if x % 2 == 0 { 0 } else { 65535 };
+            Rgba {
+                data: [v, v, v, 65535],
+            }
+        });
+        let resized = resize_linear_deep(&img, 8, 8, FilterType::Triangle);
+        let pixel = resized.get_pixel(4, 4).data;
+        assert!((i32::from(pixel[0]) - 188 * 257).abs() <= 2 * 257);
+        assert_eq!(pixel[3], 65535);
+    }
+}
@@ -0,0 +1,222 @@
+use image::{DynamicImage, ImageBuffer, Rgba};
+use libcore::errors::*;
+use png::HasParameters;
+use std::io::{Cursor, Write};
+
+/// 16-bit pixels, whatever the channel layout of the original
+pub type Rgba16Image = ImageBuffer<Rgba<u16>, Vec<u16>>;
+
+/// What to do with originals of more than 8 bits per channel
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum DepthPolicy {
+    /// Keep 16 bits per channel when the output format supports it
+    Preserve,
+    /// Reduce to 8 bits per channel with error diffusion
+    Dither,
+}
+
+/// A decoded 16-bit original
+#[derive(Clone)]
+pub(crate) struct DeepImage {
+    pub buffer: Rgba16Image,
+    /// Channels of the original: 1 (gray), 2 (gray + alpha), 3 (RGB) or 4 (RGBA)
+    pub channels: u8,
+}
+
+impl DeepImage {
+    /// Decode 16-bit PNG and TIFF originals, other images return `None`
+    pub fn decode(data: &[u8]) -> Result<Option<DeepImage>> {
+        if data.starts_with(b"\x89PNG") {
+            decode_png(data)
+        } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+            decode_tiff(data)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn from_samples(width: u32, height: u32, channels: u8, samples: &[u16]) -> Result<DeepImage> {
+        let n = channels as usize;
+        if samples.len() < (width * height) as usize * n {
+            return Err(err_msg("Truncated 16-bit image data"));
+        }
+        let buffer = ImageBuffer::from_fn(width, height, |x, y| {
+            let i = (y * width + x) as usize * n;
+            let data = match channels {
+                1 => [samples[i], samples[i], samples[i], u16::max_value()],
+                2 => [samples[i], samples[i], samples[i], samples[i + 1]],
+                3 => [samples[i], samples[i + 1], samples[i + 2], u16::max_value()],
+                _ => [samples[i], samples[i + 1], samples[i + 2], samples[i + 3]],
+            };
+            Rgba { data }
+        });
+        Ok(DeepImage { buffer, channels })
+    }
+
+    fn samples(&self) -> Vec<u16> {
+        let n = self.channels as usize;
+        let mut samples = Vec::with_capacity(self.buffer.len() / 4 * n);
+        for pixel in self.buffer.pixels() {
+            let p = pixel.data;
+            match self.channels {
+                1 => samples.push(luma(p)),
+                2 => samples.extend_from_slice(&[luma(p), p[3]]),
+                3 => samples.extend_from_slice(&p[..3]),
+                _ => samples.extend_from_slice(&p),
+            }
+        }
+        samples
+    }
+
+    /// Encode as a 16-bit PNG
+    pub fn write_png<W: Write>(&self, w: W) -> Result<()> {
+        let color_type = match self.channels {
+            1 => png::ColorType::Grayscale,
+            2 => png::ColorType::GrayscaleAlpha,
+            3 => png::ColorType::RGB,
+            _ => png::ColorType::RGBA,
+        };
+        let mut bytes = Vec::with_capacity(self.buffer.len() * 2);
+        for sample in self.samples() {
+            bytes.push((sample >> 8) as u8);
+            bytes.push(sample as u8);
+        }
+        let mut encoder = png::Encoder::new(w, self.buffer.width(), self.buffer.height());
+        encoder.set(color_type).set(png::BitDepth::Sixteen);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&bytes)?;
+        Ok(())
+    }
+
+    /// Reduce to 8 bits per channel with Floyd-Steinberg error diffusion
+    pub fn dither(&self) -> DynamicImage {
+        let (width, height) = self.buffer.dimensions();
+        let w = width as usize;
+        let mut current = vec![0f32; (w + 2) * 4];
+        let mut next = vec![0f32; (w + 2) * 4];
+        let mut out: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let i = x as usize + 1;
+                let mut data = [0u8; 4];
+                for c in 0..4 {
+                    let value =
+                        f32::from(self.buffer.get_pixel(x, y).data[c]) / 257.0 + current[i * 4 + c];
+                    let quantized = value.round().max(0.0).min(255.0);
+                    let error = value - quantized;
+                    current[(i + 1) * 4 + c] += error * 7.0 / 16.0;
+                    next[(i - 1) * 4 + c] += error * 3.0 / 16.0;
+                    next[i * 4 + c] += error * 5.0 / 16.0;
+                    next[(i + 1) * 4 + c] += error / 16.0;
+                    data[c] = quantized as u8;
+                }
+                out.put_pixel(x, y, Rgba { data });
+            }
+            std::mem::swap(&mut current, &mut next);
+            for e in next.iter_mut() {
+                *e = 0.0;
+            }
+        }
+        let img = DynamicImage::ImageRgba8(out);
+        match self.channels {
+            1 => DynamicImage::ImageLuma8(img.to_luma()),
+            2 => DynamicImage::ImageLumaA8(img.to_luma_alpha()),
+            3 => DynamicImage::ImageRgb8(img.to_rgb()),
+            _ => img,
+        }
+    }
+
+    /// Feed the pixels to a hasher, matching the role of `raw_pixels` for 8-bit images
+    pub fn hash_pixels<H: std::hash::Hasher>(&self, hasher: &mut H) {
+        for sample in self.buffer.iter() {
+            hasher.write_u16(*sample);
+        }
+    }
+}
+
+fn luma(p: [u16; 4]) -> u16 {
+    // The channels are equal for grayscale originals
+    p[0]
+}
+
+fn decode_png(data: &[u8]) -> Result<Option<DeepImage>> {
+    let mut decoder = png::Decoder::new(data);
+    // 16-bit images are never palette based, the raw samples are all we need
+    decoder.set(png::Transformations::IDENTITY);
+    let (info, mut reader) = decoder.read_info()?;
+    if info.bit_depth != png::BitDepth::Sixteen {
+        return Ok(None);
+    }
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        png::ColorType::Indexed => return Ok(None),
+    };
+    let mut bytes = vec![0; info.buffer_size()];
+    reader.next_frame(&mut bytes)?;
+    // PNG samples are big-endian
+    let samples: Vec<u16> = bytes
+        .chunks(2)
+        .map(|b| (u16::from(b[0]) << 8) | u16::from(b[1]))
+        .collect();
+    DeepImage::from_samples(info.width, info.height, channels, &samples).map(Some)
+}
+
+fn decode_tiff(data: &[u8]) -> Result<Option<DeepImage>> {
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data))?;
+    let channels = match decoder.colortype()? {
+        tiff::ColorType::Gray(16) => 1,
+        tiff::ColorType::GrayA(16) => 2,
+        tiff::ColorType::RGB(16) => 3,
+        tiff::ColorType::RGBA(16) => 4,
+        _ => return Ok(None),
+    };
+    let (width, height) = decoder.dimensions()?;
+    match decoder.read_image()? {
+        tiff::decoder::DecodingResult::U16(samples) => {
+            DeepImage::from_samples(width, height, channels, &samples).map(Some)
+        }
+        tiff::decoder::DecodingResult::U8(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png_round_trip() {
+        let samples: Vec<u16> = (0..48).map(|v| v * 1000 + 7).collect();
+        let img = DeepImage::from_samples(4, 4, 3, &samples).unwrap();
+        let mut encoded = Vec::new();
+        img.write_png(&mut encoded).unwrap();
+        let decoded = DeepImage::decode(&encoded).unwrap().unwrap();
+        assert_eq!(decoded.channels, 3);
+        assert_eq!(decoded.samples(), samples);
+        assert_eq!(decoded.dither().to_rgb().get_pixel(0, 0).data[0], 0);
+    }
+
+    #[test]
+    fn test_preserve_fallback() {
+        let samples: Vec<u16> = (0..48).map(|v| v * 1000 + 7).collect();
+        let mut encoded = Vec::new();
+        let img = DeepImage::from_samples(4, 4, 3, &samples).unwrap();
+        img.write_png(&mut encoded).unwrap();
+        let mut opts = crate::ImageOption::new(
+            "../../originals",
+            "../../outputs",
+            image::FilterType::Lanczos3,
+        );
+        opts.depth(DepthPolicy::Preserve);
+        // TIFF outputs are not supported
+        let mut img_info = crate::ImageInfo::new("deep", "tiff", Some(2), None);
+        assert_eq!(img_info.output_format(), "png");
+        // Convolution only works on 8-bit images
+        img_info.kernel("sharpen");
+        let hash = crate::resize_data(&opts, &img_info, &encoded).unwrap();
+        let output = std::fs::read(format!("../../outputs/{}.png", hash)).unwrap();
+        assert!(DeepImage::decode(&output).unwrap().is_none());
+    }
+}
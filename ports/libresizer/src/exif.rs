@@ -0,0 +1,208 @@
+/// The commonly displayed EXIF fields
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExifSummary {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// `DateTimeOriginal`, or `DateTime` when missing, as `YYYY:MM:DD HH:MM:SS`
+    pub date_time: Option<String>,
+    pub orientation: Option<u16>,
+    /// Latitude and longitude in decimal degrees
+    pub gps: Option<(f64, f64)>,
+}
+
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_GPS_LATITUDE_REF: u16 = 1;
+const TAG_GPS_LATITUDE: u16 = 2;
+const TAG_GPS_LONGITUDE_REF: u16 = 3;
+const TAG_GPS_LONGITUDE: u16 = 4;
+
+struct Entry {
+    tag: u16,
+    kind: u16,
+    count: u32,
+    /// Offset of the value, inline in the entry when it fits in 4 bytes
+    pos: usize,
+}
+
+/// A TIFF structure, as carried by the EXIF blocks
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let b = self.data.get(pos..pos + 2)?;
+        Some(if self.little_endian {
+            u16::from(b[0]) | u16::from(b[1]) << 8
+        } else {
+            u16::from(b[0]) << 8 | u16::from(b[1])
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let (a, b) = (self.u16_at(pos)?, self.u16_at(pos + 2)?);
+        Some(if self.little_endian {
+            u32::from(a) | u32::from(b) << 16
+        } else {
+            u32::from(a) << 16 | u32::from(b)
+        })
+    }
+
+    fn entries(&self, offset: usize) -> Vec<Entry> {
+        let count = self.u16_at(offset).unwrap_or(0) as usize;
+        (0..count)
+            .filter_map(|i| {
+                let at = offset + 2 + i * 12;
+                let kind = self.u16_at(at + 2)?;
+                let count = self.u32_at(at + 4)?;
+                let size = match kind {
+                    3 => 2,
+                    4 | 9 => 4,
+                    5 | 10 => 8,
+                    _ => 1,
+                } * count as usize;
+                let pos = if size <= 4 {
+                    at + 8
+                } else {
+                    self.u32_at(at + 8)? as usize
+                };
+                Some(Entry {
+                    tag: self.u16_at(at)?,
+                    kind,
+                    count,
+                    pos,
+                })
+            })
+            .collect()
+    }
+
+    fn ascii(&self, entry: &Entry) -> Option<String> {
+        let bytes = self.data.get(entry.pos..entry.pos + entry.count as usize)?;
+        let text = String::from_utf8_lossy(bytes);
+        let text = text.trim_end_matches('\0').trim();
+        if text.is_empty() {
+            None
+        } else {
+            Some(text.to_string())
+        }
+    }
+
+    fn short(&self, entry: &Entry) -> Option<u16> {
+        match entry.kind {
+            3 => self.u16_at(entry.pos),
+            4 => self.u32_at(entry.pos).map(|v| v as u16),
+            _ => None,
+        }
+    }
+
+    fn long(&self, entry: &Entry) -> Option<usize> {
+        match entry.kind {
+            4 | 13 => self.u32_at(entry.pos).map(|v| v as usize),
+            _ => None,
+        }
+    }
+
+    /// Degrees, minutes and seconds as decimal degrees
+    fn degrees(&self, entry: &Entry) -> Option<f64> {
+        if entry.kind != 5 || entry.count != 3 {
+            return None;
+        }
+        let mut degrees = 0.0;
+        for (i, unit) in [1.0, 60.0, 3600.0].iter().enumerate() {
+            let num = self.u32_at(entry.pos + i * 8)?;
+            let den = self.u32_at(entry.pos + i * 8 + 4)?;
+            if den != 0 {
+                degrees += f64::from(num) / f64::from(den) / unit;
+            }
+        }
+        Some(degrees)
+    }
+}
+
+/// Summarize a raw EXIF block, `None` when it is not a TIFF structure
+pub fn summarize(exif: &[u8]) -> Option<ExifSummary> {
+    let tiff = Tiff::new(exif)?;
+    let mut summary = ExifSummary::default();
+    let ifd0 = tiff.u32_at(4)? as usize;
+    let mut exif_ifd = None;
+    let mut gps_ifd = None;
+    for entry in tiff.entries(ifd0) {
+        match entry.tag {
+            TAG_MAKE => summary.make = tiff.ascii(&entry),
+            TAG_MODEL => summary.model = tiff.ascii(&entry),
+            TAG_ORIENTATION => summary.orientation = tiff.short(&entry),
+            TAG_DATE_TIME => summary.date_time = tiff.ascii(&entry),
+            TAG_EXIF_IFD => exif_ifd = tiff.long(&entry),
+            TAG_GPS_IFD => gps_ifd = tiff.long(&entry),
+            _ => (),
+        }
+    }
+    if let Some(offset) = exif_ifd {
+        for entry in tiff.entries(offset) {
+            if entry.tag == TAG_DATE_TIME_ORIGINAL {
+                if let Some(date_time) = tiff.ascii(&entry) {
+                    summary.date_time = Some(date_time);
+                }
+            }
+        }
+    }
+    if let Some(offset) = gps_ifd {
+        let (mut lat, mut lon) = (None, None);
+        let (mut lat_sign, mut lon_sign) = (1.0, 1.0);
+        for entry in tiff.entries(offset) {
+            match entry.tag {
+                TAG_GPS_LATITUDE_REF if tiff.ascii(&entry) == Some("S".to_string()) => {
+                    lat_sign = -1.0
+                }
+                TAG_GPS_LONGITUDE_REF if tiff.ascii(&entry) == Some("W".to_string()) => {
+                    lon_sign = -1.0
+                }
+                TAG_GPS_LATITUDE => lat = tiff.degrees(&entry),
+                TAG_GPS_LONGITUDE => lon = tiff.degrees(&entry),
+                _ => (),
+            }
+        }
+        if let (Some(lat), Some(lon)) = (lat, lon) {
+            summary.gps = Some((lat * lat_sign, lon * lon_sign));
+        }
+    }
+    Some(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        // Big-endian, IFD0 with Orientation = 6 and a 5 byte Make stored after the IFD
+        let mut exif = b"MM\0*\0\0\0\x08\0\x02".to_vec();
+        exif.extend_from_slice(&[0x01, 0x0F, 0, 2, 0, 0, 0, 5, 0, 0, 0, 38]);
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif.extend_from_slice(b"Irir\0");
+        let summary = summarize(&exif).unwrap();
+        assert_eq!(summary.make, Some("Irir".to_string()));
+        assert_eq!(summary.orientation, Some(6));
+        assert_eq!(summary.gps, None);
+    }
+}
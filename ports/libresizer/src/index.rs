@@ -0,0 +1,123 @@
+use super::*;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+const INDEX_FILE: &str = "outputs.idx";
+
+/// Request hashes and the outputs generated for them, persisted in the output directory so the
+/// outputs are reused after a restart. A `request\toutput` line is appended per new output,
+/// e.g. `3f2a…\t1234.png`.
+///
+/// The request hashes come from `cache_key`, they change with the options and the originals so
+/// the stale entries are missed.
+pub struct OutputIndex {
+    path: PathBuf,
+    outputs: HashMap<u64, String>,
+}
+
+impl OutputIndex {
+    /// Read the index of the output directory, dropping the outputs deleted since
+    pub fn load(output_dir: &str) -> Result<Self> {
+        let mut path = PathBuf::from(output_dir);
+        path.push(INDEX_FILE);
+        let mut outputs = HashMap::new();
+        let mut stale = false;
+        if let Ok(index) = fs::read_to_string(&path) {
+            for line in index.lines() {
+                let fields: Vec<&str> = line.split('\t').collect();
+                let key = match fields.as_slice() {
+                    [key_s, fname] if is_output(fname) => u64::from_str_radix(key_s, 16).ok(),
+                    _ => None,
+                };
+                let mut opath = PathBuf::from(output_dir);
+                match key {
+                    Some(key) => {
+                        opath.push(fields[1]);
+                        if opath.is_file() {
+                            // Later lines win, the earlier ones are compacted away
+                            stale |= outputs.insert(key, fields[1].to_string()).is_some();
+                        } else {
+                            stale = true;
+                        }
+                    }
+                    None => stale = true,
+                }
+            }
+        }
+        let index = OutputIndex { path, outputs };
+        if stale {
+            index.rewrite()?;
+        }
+        Ok(index)
+    }
+
+    /// The output of a request, unless its file was deleted since
+    pub fn get(&self, key: u64) -> Option<u64> {
+        let fname = self.outputs.get(&key)?;
+        let mut opath = self.path.clone();
+        opath.set_file_name(fname);
+        if opath.is_file() {
+            fname.split('.').next()?.parse().ok()
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&mut self, key: u64, output: u64, format: &str) -> Result<()> {
+        let fname = format!("{}.{}", output, format);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(format!("{:016x}\t{}\n", key, fname).as_bytes())?;
+        self.outputs.insert(key, fname);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    fn rewrite(&self) -> Result<()> {
+        let lines: Vec<String> = self
+            .outputs
+            .iter()
+            .map(|(key, fname)| format!("{:016x}\t{}\n", key, fname))
+            .collect();
+        fs::write(&self.path, lines.concat())?;
+        Ok(())
+    }
+}
+
+/// `{hash}.{format}`, nothing outside of the output directory
+fn is_output(fname: &str) -> bool {
+    let mut parts = fname.splitn(2, '.');
+    parts.next().map(|h| h.parse::<u64>().is_ok()) == Some(true)
+        && parts
+            .next()
+            .map(|e| !e.is_empty() && e.chars().all(|c| c.is_ascii_alphanumeric()))
+            == Some(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index() {
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let img_info = ImageInfo::new("ferris", "png", Some(120), None);
+        let output = resize(&opts, &img_info).unwrap();
+        let mut index = OutputIndex::load(opts.output_dir()).unwrap();
+        index.insert(img_info.to_hash(), output, "png").unwrap();
+        index.insert(1, 1, "missing").unwrap();
+        let reloaded = OutputIndex::load(opts.output_dir()).unwrap();
+        assert_eq!(reloaded.get(img_info.to_hash()), Some(output));
+        assert_eq!(reloaded.get(1), None);
+    }
+}
@@ -1,18 +1,53 @@
 pub use image::FilterType;
-use image::{DynamicImage, ImageError};
+use image::{
+    imageops, DynamicImage, GenericImageView, ImageDecoder, ImageFormat, ImageOutputFormat,
+};
 use libcore::errors::*;
+use sha2::{Digest, Sha256};
 use std::collections::hash_map::DefaultHasher;
+use std::fs::File;
 use std::hash::{Hash, Hasher};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 use std::u32::MAX;
 
+mod color;
+mod deep;
+mod exif;
+mod index;
+mod metadata;
 pub mod more;
+mod registry;
+mod spec;
+
+use self::deep::DeepImage;
+pub use self::deep::DepthPolicy;
+pub use self::deep::Rgba16Image;
+pub use self::exif::ExifSummary;
+pub use self::index::OutputIndex;
+pub use self::metadata::IccPolicy;
+use self::metadata::Metadata;
+use self::more::chain::Operation;
+pub use self::registry::HandlerRegistry;
+pub use self::spec::TransformSpec;
+
+/// Numbers the outputs being written, see `save_output`
+static TMP_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Clone)]
 pub struct ImageOption {
     input_dir: String,
     output_dir: String,
     filter_type: FilterType,
+    strip_metadata: bool,
+    icc_policy: IccPolicy,
+    icc_target: Option<String>,
+    linear_light: bool,
+    depth_policy: DepthPolicy,
+    registry: Arc<HandlerRegistry>,
 }
 
 impl ImageOption {
@@ -21,9 +56,52 @@ impl ImageOption {
             input_dir: input.to_string(),
             output_dir: output.to_string(),
             filter_type: filter_type,
+            strip_metadata: true,
+            icc_policy: IccPolicy::Keep,
+            icc_target: None,
+            linear_light: false,
+            depth_policy: DepthPolicy::Dither,
+            registry: Arc::new(HandlerRegistry::new()),
         }
     }
 
+    /// Custom operations for the chains of `more::chain`
+    pub fn handlers(&mut self, registry: HandlerRegistry) -> &mut Self {
+        self.registry = Arc::new(registry);
+        self
+    }
+
+    pub fn handler_registry(&self) -> &HandlerRegistry {
+        &self.registry
+    }
+
+    pub fn metadata(&mut self, strip: bool) -> &mut Self {
+        self.strip_metadata = strip;
+        self
+    }
+
+    pub fn icc(&mut self, policy: IccPolicy) -> &mut Self {
+        self.icc_policy = policy;
+        self
+    }
+
+    /// ICC profile file used by `IccPolicy::Convert` instead of sRGB
+    pub fn icc_target(&mut self, path: &str) -> &mut Self {
+        self.icc_target = Some(path.to_string());
+        self
+    }
+
+    /// Resample in linear light instead of gamma-encoded sRGB
+    pub fn linear(&mut self, enabled: bool) -> &mut Self {
+        self.linear_light = enabled;
+        self
+    }
+
+    pub fn depth(&mut self, policy: DepthPolicy) -> &mut Self {
+        self.depth_policy = policy;
+        self
+    }
+
     pub fn input_dir(&self) -> &str {
         self.input_dir.as_str()
     }
@@ -33,35 +111,99 @@ impl ImageOption {
     pub fn filter_type(&self) -> FilterType {
         self.filter_type
     }
+    pub fn strip_metadata(&self) -> bool {
+        self.strip_metadata
+    }
+    pub fn icc_policy(&self) -> IccPolicy {
+        self.icc_policy
+    }
+    pub fn icc_target_path(&self) -> Option<&str> {
+        self.icc_target.as_ref().map(|p| p.as_str())
+    }
+    pub fn linear_light(&self) -> bool {
+        self.linear_light
+    }
+    pub fn depth_policy(&self) -> DepthPolicy {
+        self.depth_policy
+    }
+
+    /// The settings changing the outputs, part of the keys of `cache_key`. Custom handlers only
+    /// count in the keys of the requests using them, see `ImageInfo::to_hash_with`.
+    pub fn fingerprint(&self) -> u64 {
+        let mut s = StableHasher::new();
+        filter_name(self.filter_type).hash(&mut s);
+        self.strip_metadata.hash(&mut s);
+        self.icc_policy.hash(&mut s);
+        self.icc_target.hash(&mut s);
+        self.linear_light.hash(&mut s);
+        self.depth_policy.hash(&mut s);
+        s.finish()
+    }
 }
 
+/// SHA-256 behind `Hasher`, for the keys written to disk: unlike `DefaultHasher`, its output
+/// does not change with the Rust version
+struct StableHasher(Sha256);
+
+impl StableHasher {
+    fn new() -> Self {
+        StableHasher(Sha256::new())
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.input(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().result();
+        digest[..8].iter().fold(0, |h, b| h << 8 | u64::from(*b))
+    }
+}
+
+/// An original and what to do with it. The operations and output options are kept in a
+/// `TransformSpec`, the setters below add to it.
 #[derive(Debug, Clone, Hash, Default)]
 pub struct ImageInfo {
     name: String,
     format: String,
-    width: Option<u32>,
-    height: Option<u32>,
-    blur_level: Option<u32>,
-    crop_x: Option<u32>,
-    crop_y: Option<u32>,
-    crop_w: Option<u32>,
-    crop_h: Option<u32>,
+    strip_metadata: Option<bool>,
+    icc_policy: Option<IccPolicy>,
+    linear_light: Option<bool>,
+    depth_policy: Option<DepthPolicy>,
+    iiif: Option<more::iiif::IiifParams>,
+    spec: TransformSpec,
 }
 
 impl ImageInfo {
     pub fn new(name: &str, format: &str, width: Option<u32>, height: Option<u32>) -> ImageInfo {
+        let mut spec = TransformSpec::default();
+        if width.is_some() || height.is_some() {
+            spec.operations.push(Operation::Resize { width, height });
+        }
         ImageInfo {
             name: name.to_string(),
             format: format.to_string(),
-            width,
-            height,
+            spec,
             ..Default::default()
         }
     }
 
     pub fn to_hash(&self) -> u64 {
-        let mut s = DefaultHasher::new();
+        let mut s = StableHasher::new();
+        self.hash(&mut s);
+        s.finish()
+    }
+
+    /// The cache key of a pipeline of custom handlers on this image
+    pub fn to_hash_with(&self, handlers: &[&ImageHandler]) -> u64 {
+        let mut s = StableHasher::new();
         self.hash(&mut s);
+        for handler in handlers {
+            handler.name().hash(&mut s);
+            handler.cache_key().hash(&mut s);
+        }
         s.finish()
     }
 
@@ -73,15 +215,50 @@ impl ImageInfo {
         self.format.as_str()
     }
 
-    pub fn blur_level(&self) -> Option<u32> {
-        self.blur_level
+    /// The format of the output, the format of the original unless overridden. Originals in
+    /// a format that can not be encoded, e.g. TIFF, give PNG outputs.
+    pub fn output_format(&self) -> &str {
+        match &self.spec.format {
+            Some(format) => format.as_str(),
+            None if output_format(&self.format).is_ok() => self.format.as_str(),
+            None => "png",
+        }
+    }
+
+    pub fn output(&mut self, format: &str) -> &Self {
+        self.spec.format = Some(format.to_string());
+        self
     }
 
+    /// Replace the operations and output options
+    pub fn transform(&mut self, spec: TransformSpec) -> &Self {
+        self.spec = spec;
+        self
+    }
+
+    pub fn transform_spec(&self) -> &TransformSpec {
+        &self.spec
+    }
+
+    /// Blur after the other operations
     pub fn blur(&mut self, level: u32) -> &ImageInfo {
-        self.blur_level = Some(level);
+        self.spec.set(Operation::Blur(level));
         self
     }
 
+    #[deprecated(note = "the operations are in `transform_spec`")]
+    pub fn blur_level(&self) -> Option<u32> {
+        self.spec
+            .operations
+            .iter()
+            .filter_map(|op| match op {
+                Operation::Blur(level) => Some(*level),
+                _ => None,
+            })
+            .next()
+    }
+
+    /// Crop the original before the other operations, only denoising comes first
     pub fn crop(
         &mut self,
         x: Option<u32>,
@@ -89,15 +266,122 @@ impl ImageInfo {
         width: Option<u32>,
         height: Option<u32>,
     ) -> &Self {
-        self.crop_x = x;
-        self.crop_y = y;
-        self.crop_w = width;
-        self.crop_h = height;
+        let operations = &mut self.spec.operations;
+        operations.retain(|op| match op {
+            Operation::Crop { .. } => false,
+            _ => true,
+        });
+        let index = operations
+            .iter()
+            .take_while(|op| match op {
+                Operation::Denoise(_) => true,
+                _ => false,
+            })
+            .count();
+        operations.insert(
+            index,
+            Operation::Crop {
+                x: x.unwrap_or(0),
+                y: y.unwrap_or(0),
+                width,
+                height,
+            },
+        );
         self
     }
 
+    #[deprecated(note = "the operations are in `transform_spec`")]
     pub fn croped(&self) -> bool {
-        !(self.crop_x == None && self.crop_y == None && self.crop_w == None && self.crop_h == None)
+        self.spec.operations.iter().any(|op| match op {
+            Operation::Crop { .. } => true,
+            _ => false,
+        })
+    }
+
+    /// Override the `ImageOption` metadata setting for this request
+    pub fn metadata(&mut self, strip: bool) -> &Self {
+        self.strip_metadata = Some(strip);
+        self
+    }
+
+    /// Override the `ImageOption` ICC profile setting for this request
+    pub fn icc(&mut self, policy: IccPolicy) -> &Self {
+        self.icc_policy = Some(policy);
+        self
+    }
+
+    /// Override the `ImageOption` resampling light setting for this request
+    pub fn linear(&mut self, enabled: bool) -> &Self {
+        self.linear_light = Some(enabled);
+        self
+    }
+
+    /// Reach the size by removing seams instead of stretching
+    pub fn liquid(&mut self, enabled: bool) -> &Self {
+        for op in self.spec.operations.iter_mut() {
+            *op = match op {
+                Operation::Resize { width, height } if enabled => Operation::Liquid {
+                    width: *width,
+                    height: *height,
+                },
+                Operation::Liquid { width, height } if !enabled => Operation::Resize {
+                    width: *width,
+                    height: *height,
+                },
+                _ => continue,
+            };
+        }
+        self
+    }
+
+    /// Convolve with a kernel of `more::convolve::gen_kernel`
+    pub fn kernel(&mut self, kernel: &str) -> &Self {
+        self.spec.set(Operation::Convolve(kernel.to_string()));
+        self
+    }
+
+    /// Reduce the noise of the original before it is resized
+    pub fn denoise(&mut self, denoise: more::denoise::Denoise) -> &Self {
+        let operations = &mut self.spec.operations;
+        operations.retain(|op| match op {
+            Operation::Denoise(_) => false,
+            _ => true,
+        });
+        operations.insert(0, Operation::Denoise(denoise.to_string()));
+        self
+    }
+
+    /// Correct the levels of the resized image
+    pub fn levels(&mut self, levels: more::levels::Levels) -> &Self {
+        self.spec.set(Operation::Levels(levels.to_string()));
+        self
+    }
+
+    /// Render a cosmetic effect over the resized image
+    pub fn effect(&mut self, effect: more::effects::Effect) -> &Self {
+        self.spec.set(Operation::Effect(effect.to_string()));
+        self
+    }
+
+    /// JPEG quality, from 1 to 100, instead of 75
+    pub fn quality(&mut self, quality: u8) -> &Self {
+        self.spec.quality = Some(quality);
+        self
+    }
+
+    pub fn iiif(&mut self, params: more::iiif::IiifParams) -> &Self {
+        self.iiif = Some(params);
+        self
+    }
+
+    pub fn iiif_params(&self) -> Option<&more::iiif::IiifParams> {
+        self.iiif.as_ref()
+    }
+
+    /// Override the `ImageOption` bit depth setting for this request
+    pub fn depth(&mut self, policy: DepthPolicy) -> &Self {
+        self.depth_policy = Some(policy);
+        self
     }
 }
 
@@ -112,35 +396,246 @@ pub fn gen_filter_type(filter_type_s: &str) -> Result<FilterType> {
     }
 }
 
-fn pipeline(opts: &ImageOption, img_info: &ImageInfo, handlers: Vec<&ImageHandler>) -> Result<u64> {
+fn filter_name(filter_type: FilterType) -> &'static str {
+    match filter_type {
+        FilterType::Nearest => "nearest",
+        FilterType::Triangle => "triangle",
+        FilterType::CatmullRom => "catmullrom",
+        FilterType::Gaussian => "gaussian",
+        FilterType::Lanczos3 => "lanczos3",
+    }
+}
+
+pub fn gen_icc_policy(policy_s: &str) -> Result<IccPolicy> {
+    match policy_s.to_lowercase().as_str() {
+        "keep" => Ok(IccPolicy::Keep),
+        "strip" => Ok(IccPolicy::Strip),
+        "convert" => Ok(IccPolicy::Convert),
+        _ => Err(err_msg("Unknown IccPolicy")),
+    }
+}
+
+pub fn gen_depth_policy(policy_s: &str) -> Result<DepthPolicy> {
+    match policy_s.to_lowercase().as_str() {
+        "preserve" | "16" => Ok(DepthPolicy::Preserve),
+        "dither" | "8" => Ok(DepthPolicy::Dither),
+        _ => Err(err_msg("Unknown DepthPolicy")),
+    }
+}
+
+/// Width and height of an original
+pub fn dimensions(opts: &ImageOption, fname: &str) -> Result<(u32, u32)> {
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(fname);
+    decoder_dimensions(BufReader::new(File::open(&fpath)?))
+}
+
+/// Load the original of `img_info`, run it through the handlers in order and save the output,
+/// named after the returned hash
+pub fn pipeline(
+    opts: &ImageOption,
+    img_info: &ImageInfo,
+    handlers: Vec<&ImageHandler>,
+) -> Result<u64> {
     // Load original image
     let mut fpath = PathBuf::from(&opts.input_dir());
     fpath.push(img_info.fname());
-    let o_result = image::open(&fpath);
-    if let Err(ImageError::IoError(e)) = o_result {
-        return Err(Error::from(e));
+    let data = std::fs::read(&fpath)?;
+    let decoded = decode(opts, img_info, &data, &handlers)?;
+    run_pipeline(opts, img_info, &data, decoded, handlers)
+}
+
+/// Like `pipeline`, for an image already in memory instead of an original, e.g. an upload.
+/// Data that can not be decoded is an invalid parameter.
+pub fn pipeline_data(
+    opts: &ImageOption,
+    img_info: &ImageInfo,
+    data: &[u8],
+    handlers: Vec<&ImageHandler>,
+) -> Result<u64> {
+    let decoded = decode(opts, img_info, data, &handlers)
+        .map_err(|e| invalid_param(format!("Undecodable image: {}", e)))?;
+    run_pipeline(opts, img_info, data, decoded, handlers)
+}
+
+/// The pixels of an image, 16 bits ones only when every handler works on them
+enum Pixels {
+    Deep(DeepImage),
+    Flat(DynamicImage),
+}
+
+fn decode(
+    opts: &ImageOption,
+    img_info: &ImageInfo,
+    data: &[u8],
+    handlers: &[&ImageHandler],
+) -> Result<(Metadata, Pixels)> {
+    let mut metadata = output_metadata(opts, img_info, data)?;
+    let icc_policy = img_info.icc_policy.unwrap_or(opts.icc_policy());
+    let depth_policy = img_info.depth_policy.unwrap_or(opts.depth_policy());
+    // Dithered to 8 bits unless every handler works on 16-bit images
+    let preserve = depth_policy == DepthPolicy::Preserve
+        && img_info.output_format() == "png"
+        && handlers.iter().all(|h| h.supports_deep());
+    match DeepImage::decode(data)? {
+        Some(deep) if preserve => {
+            // Colour conversion is 8-bit only
+            if icc_policy == IccPolicy::Convert {
+                metadata.icc = Metadata::read(data)?.icc;
+            }
+            Ok((metadata, Pixels::Deep(deep)))
+        }
+        Some(deep) => Ok((metadata, Pixels::Flat(deep.dither()))),
+        None => Ok((metadata, Pixels::Flat(image::load_from_memory(data)?))),
     }
-    let img = o_result?;
-    // Recursive call handler
-    let result_img = pipeline_each(img, 0, handlers)?;
-    // Get hash
+}
+
+fn run_pipeline(
+    opts: &ImageOption,
+    img_info: &ImageInfo,
+    data: &[u8],
+    (mut metadata, pixels): (Metadata, Pixels),
+    handlers: Vec<&ImageHandler>,
+) -> Result<u64> {
+    let icc_policy = img_info.icc_policy.unwrap_or(opts.icc_policy());
     let mut hasher = DefaultHasher::new();
-    hasher.write(&result_img.raw_pixels());
+    let mut encoded = Vec::new();
+    match pixels {
+        Pixels::Deep(deep) => {
+            // 16-bit pixels go through the handlers untouched
+            let result_img = pipeline_deep(deep, handlers)?;
+            result_img.hash_pixels(&mut hasher);
+            result_img.write_png(&mut encoded)?;
+        }
+        Pixels::Flat(img) => {
+            // CMYK originals are converted whatever the policy, their profile would not describe
+            // the decoded RGB pixels
+            let source_icc = if icc_policy == IccPolicy::Strip {
+                Metadata::read(data)?.icc.filter(|icc| color::is_cmyk(icc))
+            } else {
+                metadata.icc.take()
+            };
+            let cmyk = source_icc.as_ref().map_or(false, |icc| color::is_cmyk(icc));
+            let img = if icc_policy == IccPolicy::Convert || cmyk {
+                let (img, target_icc) = color::convert(
+                    data,
+                    img,
+                    source_icc.as_ref().map(|icc| icc.as_slice()),
+                    opts.icc_target_path(),
+                )?;
+                if icc_policy != IccPolicy::Strip {
+                    metadata.icc = Some(target_icc);
+                }
+                img
+            } else {
+                metadata.icc = source_icc;
+                img
+            };
+            // Recursive call handler
+            let result_img = pipeline_each(img, 0, handlers)?;
+            // Never tag the output with a profile of another colour space
+            if let Some(icc) = &metadata.icc {
+                if !color::describes(icc, &result_img) {
+                    metadata.icc = None;
+                }
+            }
+            hasher.write(&result_img.raw_pixels());
+            let format = match output_format(img_info.output_format())? {
+                ImageOutputFormat::JPEG(default) => {
+                    ImageOutputFormat::JPEG(img_info.spec.quality.unwrap_or(default))
+                }
+                format => format,
+            };
+            result_img.write_to(&mut encoded, format)?;
+        }
+    }
+    // The same pixels encoded at another quality are another output
+    if let Some(quality) = img_info.spec.quality {
+        hasher.write_u8(quality);
+    }
+    save_output(opts, hasher, metadata, encoded, img_info.output_format())
+}
+
+/// Write an encoded output named after the hash of its pixels and metadata, unless it exists
+fn save_output(
+    opts: &ImageOption,
+    mut hasher: DefaultHasher,
+    metadata: Metadata,
+    encoded: Vec<u8>,
+    format_s: &str,
+) -> Result<u64> {
+    // Get hash
+    if let Some(exif) = &metadata.exif {
+        hasher.write(exif);
+    }
+    if let Some(icc) = &metadata.icc {
+        hasher.write(icc);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        hasher.write(xmp);
+    }
     let hash = hasher.finish();
     let mut opath = PathBuf::from(&opts.output_dir());
     opath.push(hash.to_string());
-    opath.set_extension(
-        &fpath
-            .extension()
-            .ok_or(err_msg("Did not get the extension of the input file"))?,
-    );
+    opath.set_extension(format_s);
     // Check if the file exists
     if !Path::new(&opath).exists() {
-        result_img.save(opath)?;
+        // Written aside then renamed, requests generating the same output concurrently never
+        // serve a partial file
+        let mut tmp_path = opath.clone();
+        tmp_path.set_extension(format!(
+            "{}.{}-{}.tmp",
+            format_s,
+            std::process::id(),
+            TMP_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&tmp_path, metadata.embed(encoded)?)?;
+        std::fs::rename(&tmp_path, &opath)?;
     }
     Ok(hash)
 }
 
+/// The metadata of the original that survives into the output
+fn output_metadata(opts: &ImageOption, img_info: &ImageInfo, data: &[u8]) -> Result<Metadata> {
+    let strip = img_info.strip_metadata.unwrap_or(opts.strip_metadata());
+    let icc_policy = img_info.icc_policy.unwrap_or(opts.icc_policy());
+    if strip && icc_policy == IccPolicy::Strip {
+        return Ok(Metadata::default());
+    }
+    let mut metadata = Metadata::read(data)?;
+    if strip {
+        metadata.exif = None;
+        metadata.xmp = None;
+    }
+    if icc_policy == IccPolicy::Strip {
+        metadata.icc = None;
+    }
+    Ok(metadata)
+}
+
+fn output_format(format_s: &str) -> Result<ImageOutputFormat> {
+    match format_s.to_lowercase().as_str() {
+        "jpg" | "jpeg" => Ok(ImageOutputFormat::JPEG(75)),
+        "png" => Ok(ImageOutputFormat::PNG),
+        "gif" => Ok(ImageOutputFormat::GIF),
+        "bmp" => Ok(ImageOutputFormat::BMP),
+        "ico" => Ok(ImageOutputFormat::ICO),
+        _ => Err(err_msg("Unsupported output format")),
+    }
+}
+
+/// The MIME type of a supported output format
+pub fn mime_type(format_s: &str) -> Result<&'static str> {
+    match output_format(format_s)? {
+        ImageOutputFormat::JPEG(_) => Ok("image/jpeg"),
+        ImageOutputFormat::PNG => Ok("image/png"),
+        ImageOutputFormat::GIF => Ok("image/gif"),
+        ImageOutputFormat::BMP => Ok("image/bmp"),
+        ImageOutputFormat::ICO => Ok("image/x-icon"),
+        _ => Err(err_msg("Unsupported output format")),
+    }
+}
+
 fn pipeline_each(
     result: DynamicImage,
     begin: usize,
@@ -157,17 +652,64 @@ fn pipeline_each(
     }
 }
 
-trait ImageHandler {
+fn pipeline_deep(img: DeepImage, handlers: Vec<&ImageHandler>) -> Result<DeepImage> {
+    let mut result = img;
+    for handler in handlers {
+        result.buffer = handler.handle_deep(result.buffer)?;
+    }
+    Ok(result)
+}
+
+/// An operation of a pipeline. Downstream crates implement it for their own operations and
+/// make them available to the URLs with a `HandlerRegistry`.
+pub trait ImageHandler: Send + Sync {
+    /// Short identifier of the operation, e.g. `resize`
+    fn name(&self) -> &str;
+
+    /// The parameters changing the output, combined with the name into the cache keys of
+    /// `ImageInfo::to_hash_with`. Equal parameters give the same key, across runs; handlers
+    /// without parameters keep the default.
+    fn cache_key(&self) -> String {
+        String::new()
+    }
+
     fn handle(&self, img: DynamicImage) -> Result<DynamicImage>;
+
+    /// Handle 16-bit images, for the handlers that can work on them
+    fn handle_deep(&self, _img: Rgba16Image) -> Result<Rgba16Image> {
+        Err(err_msg("The operation does not support 16-bit images"))
+    }
+
+    /// Whether `handle_deep` is implemented, the pipelines dither the other ones to 8 bits
+    fn supports_deep(&self) -> bool {
+        false
+    }
 }
 
-struct Resizer {
-    width: Option<u32>,
-    height: Option<u32>,
-    filter_type: FilterType,
+/// Resize to the given width and/or height, keeping the aspect ratio unless both are set
+pub struct Resizer {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub filter_type: FilterType,
+    /// Resample in linear light
+    pub linear: bool,
 }
 
 impl ImageHandler for Resizer {
+    fn name(&self) -> &str {
+        "resize"
+    }
+
+    fn cache_key(&self) -> String {
+        format!(
+            "{:?}x{:?},{},{}",
+            self.width,
+            self.height,
+            filter_name(self.filter_type),
+            self.linear
+        )
+    }
+
     fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
         let width = self.width.unwrap_or(MAX);
         let height = self.height.unwrap_or(MAX);
@@ -176,6 +718,13 @@ impl ImageHandler for Resizer {
             if width == MAX && height == MAX {
                 // Original size
                 img
+            } else if self.linear {
+                let (width, height) = if width == MAX || height == MAX {
+                    color::fit_dimensions(img.dimensions(), width, height)
+                } else {
+                    (width, height)
+                };
+                color::resize_linear(&img, width, height, self.filter_type)
             } else if width == MAX || height == MAX {
                 // Preserve aspect ratio
                 img.resize(width, height, self.filter_type)
@@ -186,20 +735,131 @@ impl ImageHandler for Resizer {
         };
         Ok(resized)
     }
+
+    fn handle_deep(&self, img: Rgba16Image) -> Result<Rgba16Image> {
+        let width = self.width.unwrap_or(MAX);
+        let height = self.height.unwrap_or(MAX);
+        if width == MAX && height == MAX {
+            Ok(img)
+        } else {
+            let (width, height) = if width == MAX || height == MAX {
+                color::fit_dimensions(img.dimensions(), width, height)
+            } else {
+                (width, height)
+            };
+            if self.linear {
+                Ok(color::resize_linear_deep(
+                    &img,
+                    width,
+                    height,
+                    self.filter_type,
+                ))
+            } else {
+                Ok(imageops::resize(&img, width, height, self.filter_type))
+            }
+        }
+    }
+
+    fn supports_deep(&self) -> bool {
+        true
+    }
 }
 
+/// Run the operations of the spec of `img_info` in order, by default the resize of
+/// `ImageInfo::new`
 pub fn resize(opts: &ImageOption, img_info: &ImageInfo) -> Result<u64> {
-    pipeline(
+    let boxed = spec_handlers(opts, img_info)?;
+    pipeline(opts, img_info, boxed.iter().map(|h| h.as_ref()).collect())
+}
+
+/// Like `resize`, for an uploaded image. The name of `img_info` only identifies the output.
+pub fn resize_data(opts: &ImageOption, img_info: &ImageInfo, data: &[u8]) -> Result<u64> {
+    let boxed = spec_handlers(opts, img_info)?;
+    pipeline_data(
         opts,
         img_info,
-        vec![&Resizer {
-            width: img_info.width,
-            height: img_info.height,
-            filter_type: opts.filter_type(),
-        }],
+        data,
+        boxed.iter().map(|h| h.as_ref()).collect(),
     )
 }
 
+/// The key of the output of `resize` in a cache: the parameters of the handlers, the settings
+/// of `opts` and the size and modification time of the original are included
+pub fn cache_key(opts: &ImageOption, img_info: &ImageInfo) -> Result<u64> {
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(img_info.fname());
+    let metadata = std::fs::metadata(&fpath)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    spec_key(opts, img_info, |s| {
+        metadata.len().hash(s);
+        modified.hash(s);
+    })
+}
+
+/// Like `cache_key`, for the output of `resize_data`
+pub fn cache_key_data(opts: &ImageOption, img_info: &ImageInfo, data: &[u8]) -> Result<u64> {
+    spec_key(opts, img_info, |s| data.hash(s))
+}
+
+fn spec_key<F>(opts: &ImageOption, img_info: &ImageInfo, source: F) -> Result<u64>
+where
+    F: FnOnce(&mut StableHasher),
+{
+    let boxed = spec_handlers(opts, img_info)?;
+    let handlers: Vec<&ImageHandler> = boxed.iter().map(|h| h.as_ref()).collect();
+    let mut s = StableHasher::new();
+    s.write_u64(img_info.to_hash_with(&handlers));
+    s.write_u64(opts.fingerprint());
+    source(&mut s);
+    Ok(s.finish())
+}
+
+fn spec_handlers(opts: &ImageOption, img_info: &ImageInfo) -> Result<Vec<Box<ImageHandler>>> {
+    img_info
+        .spec
+        .operations
+        .iter()
+        .map(|operation| operation.handler(opts, img_info))
+        .collect()
+}
+
+/// The format of an uploaded image, as a file extension
+pub fn data_format(data: &[u8]) -> Result<&'static str> {
+    match image::guess_format(data)? {
+        ImageFormat::PNG => Ok("png"),
+        ImageFormat::JPEG => Ok("jpg"),
+        ImageFormat::GIF => Ok("gif"),
+        ImageFormat::BMP => Ok("bmp"),
+        ImageFormat::ICO => Ok("ico"),
+        ImageFormat::TIFF => Ok("tiff"),
+        ImageFormat::WEBP => Ok("webp"),
+        _ => Err(invalid_param("Unsupported image format")),
+    }
+}
+
+/// Width and height of an uploaded image, read from its header before decoding anything
+pub fn data_dimensions(data: &[u8]) -> Result<(u32, u32)> {
+    decoder_dimensions(Cursor::new(data)).map_err(|e| invalid_param(e.to_string()))
+}
+
+/// Width and height given by the decoder of the format, which only reads the headers
+fn decoder_dimensions<R: Read + Seek>(mut reader: R) -> Result<(u32, u32)> {
+    let mut magic = Vec::new();
+    reader.by_ref().take(16).read_to_end(&mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+    let dimensions = match image::guess_format(&magic)? {
+        ImageFormat::PNG => image::png::PNGDecoder::new(reader).dimensions()?,
+        ImageFormat::JPEG => image::jpeg::JPEGDecoder::new(reader).dimensions()?,
+        ImageFormat::GIF => image::gif::Decoder::new(reader).dimensions()?,
+        ImageFormat::BMP => image::bmp::BMPDecoder::new(reader).dimensions()?,
+        ImageFormat::ICO => image::ico::ICODecoder::new(reader)?.dimensions()?,
+        ImageFormat::TIFF => image::tiff::TIFFDecoder::new(reader)?.dimensions()?,
+        ImageFormat::WEBP => image::webp::WebpDecoder::new(reader).dimensions()?,
+        _ => return Err(invalid_param("Unsupported image format")),
+    };
+    Ok(dimensions)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -210,4 +870,52 @@ mod tests {
         let img_info = ImageInfo::new("ferris", "png", None, Some(350));
         println!("hash: {}", resize(&opts, &img_info).unwrap());
     }
+
+    #[test]
+    fn test_resize_data() {
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let data = std::fs::read("../../originals/ferris.png").unwrap();
+        assert_eq!(data_format(&data).unwrap(), "png");
+        assert_eq!(
+            data_dimensions(&data).unwrap(),
+            dimensions(&opts, "ferris.png").unwrap()
+        );
+        let upload_info = ImageInfo::new("upload", "png", Some(100), None);
+        println!("hash: {}", resize_data(&opts, &upload_info, &data).unwrap());
+        // Guessed as a PNG, undecodable
+        let e = resize_data(&opts, &upload_info, &data[..data.len() / 2]).unwrap_err();
+        assert!(e.downcast_ref::<InvalidParameter>().is_some());
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let input = std::env::temp_dir().join("irir-cache-key-test");
+        std::fs::create_dir_all(&input).unwrap();
+        let data = std::fs::read("../../originals/ferris.png").unwrap();
+        std::fs::write(input.join("ferris.png"), &data).unwrap();
+        let opts = ImageOption::new(
+            input.to_str().unwrap(),
+            "../../outputs",
+            FilterType::Lanczos3,
+        );
+        let img_info = ImageInfo::new("ferris", "png", Some(120), None);
+        let key = cache_key(&opts, &img_info).unwrap();
+        assert_eq!(cache_key(&opts, &img_info).unwrap(), key);
+        let mut index = OutputIndex::load(opts.output_dir()).unwrap();
+        index
+            .insert(key, resize(&opts, &img_info).unwrap(), "png")
+            .unwrap();
+        assert!(index.get(key).is_some());
+        // Another filter
+        let nearest = ImageOption::new(
+            input.to_str().unwrap(),
+            "../../outputs",
+            FilterType::Nearest,
+        );
+        assert!(index.get(cache_key(&nearest, &img_info).unwrap()).is_none());
+        // The original replaced
+        std::fs::write(input.join("ferris.png"), &data[..data.len() - 1]).unwrap();
+        assert!(index.get(cache_key(&opts, &img_info).unwrap()).is_none());
+        assert!(cache_key_data(&opts, &img_info, &data).is_ok());
+    }
 }
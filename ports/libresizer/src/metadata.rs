@@ -0,0 +1,328 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use libcore::errors::*;
+use log::warn;
+use std::io::{Read, Write};
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+// Segment length field (2) + ICC header (12) + sequence number (1) + count (1)
+const ICC_CHUNK_MAX: usize = 0xFFFF - 2 - 12 - 2;
+
+/// What to do with the embedded ICC profile of the original
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum IccPolicy {
+    Keep,
+    Strip,
+    /// Convert the pixels into the target profile and tag the output with it
+    Convert,
+}
+
+/// Metadata blocks carried by an original image
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// Raw TIFF structure, without the JPEG `Exif\0\0` header
+    pub exif: Option<Vec<u8>>,
+    pub icc: Option<Vec<u8>>,
+    /// XMP packet, without the JPEG namespace header or the PNG `iTXt` fields
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl Metadata {
+    pub fn read(data: &[u8]) -> Result<Metadata> {
+        if data.starts_with(&JPEG_SOI) {
+            read_jpeg(data)
+        } else if data.starts_with(&PNG_SIGNATURE) {
+            read_png(data)
+        } else {
+            Ok(Metadata::default())
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.icc.is_none() && self.xmp.is_none()
+    }
+
+    /// Insert the metadata blocks into an encoded JPEG or PNG image
+    pub fn embed(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        if self.is_empty() {
+            Ok(data)
+        } else if data.starts_with(&JPEG_SOI) {
+            self.embed_jpeg(data)
+        } else if data.starts_with(&PNG_SIGNATURE) {
+            self.embed_png(data)
+        } else {
+            // Other formats do not carry metadata, keep the pixels only
+            Ok(data)
+        }
+    }
+
+    fn embed_jpeg(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        // After SOI and the JFIF APP0 segment if present
+        let mut offset = JPEG_SOI.len();
+        if let Some((0xE0, _, end)) = jpeg_segments(&data).next() {
+            offset = end;
+        }
+        let mut segments = Vec::new();
+        if let Some(exif) = &self.exif {
+            let mut payload = EXIF_HEADER.to_vec();
+            payload.extend_from_slice(exif);
+            write_jpeg_segment(&mut segments, 0xE1, &payload)?;
+        }
+        if let Some(xmp) = &self.xmp {
+            let mut payload = XMP_HEADER.to_vec();
+            payload.extend_from_slice(xmp);
+            write_jpeg_segment(&mut segments, 0xE1, &payload)?;
+        }
+        if let Some(icc) = &self.icc {
+            let chunks: Vec<&[u8]> = icc.chunks(ICC_CHUNK_MAX).collect();
+            if chunks.len() > 255 {
+                return Err(err_msg("ICC profile is too large to embed"));
+            }
+            for (i, chunk) in chunks.iter().enumerate() {
+                let mut payload = ICC_HEADER.to_vec();
+                payload.push(i as u8 + 1);
+                payload.push(chunks.len() as u8);
+                payload.extend_from_slice(chunk);
+                write_jpeg_segment(&mut segments, 0xE2, &payload)?;
+            }
+        }
+        let mut result = Vec::with_capacity(data.len() + segments.len());
+        result.extend_from_slice(&data[..offset]);
+        result.extend_from_slice(&segments);
+        result.extend_from_slice(&data[offset..]);
+        Ok(result)
+    }
+
+    fn embed_png(&self, data: Vec<u8>) -> Result<Vec<u8>> {
+        // After IHDR, which is always the first chunk
+        let offset = png_chunks(&data)
+            .next()
+            .filter(|(kind, _, _)| kind == b"IHDR")
+            .map(|(_, _, end)| end)
+            .ok_or(err_msg("Missing IHDR chunk"))?;
+        let mut chunks = Vec::new();
+        if let Some(icc) = &self.icc {
+            let mut payload = b"ICC Profile\0\0".to_vec();
+            let mut encoder = ZlibEncoder::new(payload, Compression::default());
+            encoder.write_all(icc)?;
+            payload = encoder.finish()?;
+            write_png_chunk(&mut chunks, b"iCCP", &payload);
+        }
+        if let Some(exif) = &self.exif {
+            write_png_chunk(&mut chunks, b"eXIf", exif);
+        }
+        if let Some(xmp) = &self.xmp {
+            // Keyword, uncompressed, no language tag nor translated keyword
+            let mut payload = XMP_KEYWORD.to_vec();
+            payload.extend_from_slice(&[0, 0, 0, 0, 0]);
+            payload.extend_from_slice(xmp);
+            write_png_chunk(&mut chunks, b"iTXt", &payload);
+        }
+        let mut result = Vec::with_capacity(data.len() + chunks.len());
+        result.extend_from_slice(&data[..offset]);
+        result.extend_from_slice(&chunks);
+        result.extend_from_slice(&data[offset..]);
+        Ok(result)
+    }
+}
+
+fn read_jpeg(data: &[u8]) -> Result<Metadata> {
+    let mut meta = Metadata::default();
+    let mut icc_chunks: Vec<(u8, &[u8])> = Vec::new();
+    for (marker, payload, _) in jpeg_segments(data) {
+        if marker == 0xE1 && payload.starts_with(EXIF_HEADER) && meta.exif.is_none() {
+            meta.exif = Some(payload[EXIF_HEADER.len()..].to_vec());
+        } else if marker == 0xE1 && payload.starts_with(XMP_HEADER) && meta.xmp.is_none() {
+            meta.xmp = Some(payload[XMP_HEADER.len()..].to_vec());
+        } else if marker == 0xE2 && payload.starts_with(ICC_HEADER) {
+            let rest = &payload[ICC_HEADER.len()..];
+            if rest.len() > 2 {
+                icc_chunks.push((rest[0], &rest[2..]));
+            }
+        }
+    }
+    if !icc_chunks.is_empty() {
+        icc_chunks.sort_by_key(|(seq, _)| *seq);
+        meta.icc = Some(
+            icc_chunks
+                .iter()
+                .flat_map(|(_, c)| c.iter())
+                .cloned()
+                .collect(),
+        );
+    }
+    Ok(meta)
+}
+
+fn read_png(data: &[u8]) -> Result<Metadata> {
+    let mut meta = Metadata::default();
+    for (kind, payload, _) in png_chunks(data) {
+        match &kind {
+            // Unreadable blocks are left out, the pixels can still be decoded
+            b"iCCP" => match read_iccp(payload) {
+                Ok(icc) => meta.icc = Some(icc),
+                Err(e) => warn!("iCCP chunk skipped: {}", e),
+            },
+            b"eXIf" => meta.exif = Some(payload.to_vec()),
+            b"iTXt" if payload.starts_with(XMP_KEYWORD) => {
+                match read_itxt(&payload[XMP_KEYWORD.len()..]) {
+                    Ok(xmp) => meta.xmp = Some(xmp),
+                    Err(e) => warn!("iTXt chunk skipped: {}", e),
+                }
+            }
+            b"IDAT" => break,
+            _ => (),
+        }
+    }
+    Ok(meta)
+}
+
+/// The profile of an `iCCP` chunk
+fn read_iccp(payload: &[u8]) -> Result<Vec<u8>> {
+    // Profile name, null separator, compression method, zlib stream
+    let name_end = payload
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or(err_msg("Malformed iCCP chunk"))?;
+    let compressed = payload
+        .get(name_end + 2..)
+        .ok_or(err_msg("Malformed iCCP chunk"))?;
+    let mut icc = Vec::new();
+    ZlibDecoder::new(compressed).read_to_end(&mut icc)?;
+    Ok(icc)
+}
+
+/// The text of an `iTXt` chunk, from the null separator after its keyword
+fn read_itxt(rest: &[u8]) -> Result<Vec<u8>> {
+    let malformed = || err_msg("Malformed iTXt chunk");
+    // Null separator, compression flag and method, then the language tag and the translated
+    // keyword, both null terminated
+    let compressed = *rest.get(1).ok_or_else(malformed)? == 1;
+    let mut fields = rest.get(3..).ok_or_else(malformed)?.splitn(3, |b| *b == 0);
+    fields.next();
+    fields.next();
+    let text = fields.next().ok_or_else(malformed)?;
+    if compressed {
+        let mut xmp = Vec::new();
+        ZlibDecoder::new(text).read_to_end(&mut xmp)?;
+        Ok(xmp)
+    } else {
+        Ok(text.to_vec())
+    }
+}
+
+/// Iterate over the `(marker, payload, end offset)` of the JPEG segments before the scan data
+pub(crate) fn jpeg_segments<'a>(data: &'a [u8]) -> impl Iterator<Item = (u8, &'a [u8], usize)> {
+    let mut pos = JPEG_SOI.len();
+    std::iter::from_fn(move || {
+        while pos + 1 < data.len() && data[pos] == 0xFF && data[pos + 1] == 0xFF {
+            // Fill bytes
+            pos += 1;
+        }
+        if pos + 4 > data.len() || data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // Start of scan or end of image, no more header segments
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = ((data[pos + 2] as usize) << 8) | data[pos + 3] as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return None;
+        }
+        let payload = &data[pos + 4..end];
+        pos = end;
+        Some((marker, payload, end))
+    })
+}
+
+/// Iterate over the `(type, data, end offset)` of the PNG chunks
+pub(crate) fn png_chunks<'a>(data: &'a [u8]) -> impl Iterator<Item = ([u8; 4], &'a [u8], usize)> {
+    let mut pos = PNG_SIGNATURE.len();
+    std::iter::from_fn(move || {
+        if pos + 12 > data.len() {
+            return None;
+        }
+        let len = ((data[pos] as usize) << 24)
+            | ((data[pos + 1] as usize) << 16)
+            | ((data[pos + 2] as usize) << 8)
+            | data[pos + 3] as usize;
+        let end = pos + 12 + len;
+        if end > data.len() {
+            return None;
+        }
+        let mut kind = [0; 4];
+        kind.copy_from_slice(&data[pos + 4..pos + 8]);
+        let payload = &data[pos + 8..pos + 8 + len];
+        pos = end;
+        Some((kind, payload, end))
+    })
+}
+
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<()> {
+    let len = payload.len() + 2;
+    if len > 0xFFFF {
+        return Err(err_msg("JPEG segment is too large"));
+    }
+    out.extend_from_slice(&[0xFF, marker, (len >> 8) as u8, len as u8]);
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], payload: &[u8]) {
+    let len = payload.len() as u32;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(payload);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embed() {
+        let meta = Metadata {
+            exif: Some(b"MM\0*\0\0\0\x08\0\0".to_vec()),
+            icc: Some(vec![7; 70000]),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+        };
+        let jpeg = meta.embed(vec![0xFF, 0xD8, 0xFF, 0xD9]).unwrap();
+        let read = Metadata::read(&jpeg).unwrap();
+        assert_eq!(read.exif, meta.exif);
+        assert_eq!(read.icc, meta.icc);
+        assert_eq!(read.xmp, meta.xmp);
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut png, b"IHDR", &[0; 13]);
+        write_png_chunk(&mut png, b"IEND", &[]);
+        let read = Metadata::read(&meta.embed(png).unwrap()).unwrap();
+        assert_eq!(read.icc, meta.icc);
+        assert_eq!(read.xmp, meta.xmp);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut png, b"IHDR", &[0; 13]);
+        write_png_chunk(&mut png, b"iCCP", b"icc\0\0not zlib");
+        write_png_chunk(&mut png, b"iTXt", XMP_KEYWORD);
+        write_png_chunk(&mut png, b"eXIf", b"MM\0*");
+        write_png_chunk(&mut png, b"IEND", &[]);
+        let read = Metadata::read(&png).unwrap();
+        assert_eq!(read.icc, None);
+        assert_eq!(read.xmp, None);
+        assert!(read.exif.is_some());
+    }
+}
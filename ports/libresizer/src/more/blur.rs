@@ -6,6 +6,14 @@ pub struct BlurHandler {
 }
 
 impl ImageHandler for BlurHandler {
+    fn name(&self) -> &str {
+        "blur"
+    }
+
+    fn cache_key(&self) -> String {
+        self.sigma.to_string()
+    }
+
     fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
         if self.sigma != 0.0 {
             Ok(img.blur(self.sigma))
@@ -13,6 +21,18 @@ impl ImageHandler for BlurHandler {
             Ok(img)
         }
     }
+
+    fn handle_deep(&self, img: Rgba16Image) -> Result<Rgba16Image> {
+        if self.sigma != 0.0 {
+            Ok(image::imageops::blur(&img, self.sigma))
+        } else {
+            Ok(img)
+        }
+    }
+
+    fn supports_deep(&self) -> bool {
+        true
+    }
 }
 
 impl BlurHandler {
@@ -23,15 +43,10 @@ impl BlurHandler {
     }
 }
 
+/// Resize then blur, as set by `ImageInfo::new` and `ImageInfo::blur`
+#[deprecated(note = "the operations of `ImageInfo` all run with `resize`")]
 pub fn blur(opts: &ImageOption, img_info: &ImageInfo) -> Result<u64> {
-    let resizer = Resizer {
-        width: img_info.width,
-        height: img_info.height,
-        filter_type: opts.filter_type(),
-    };
-    let blur_handler = BlurHandler::new(img_info.blur_level());
-    let handlers: Vec<&ImageHandler> = vec![&resizer, &blur_handler];
-    pipeline(opts, img_info, handlers)
+    resize(opts, img_info)
 }
 
 #[cfg(test)]
@@ -39,6 +54,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(deprecated)]
     fn test_blur() {
         let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
         let mut img_info = ImageInfo::new("ferris", "png", None, None);
@@ -0,0 +1,345 @@
+use super::super::*;
+use super::blur::BlurHandler;
+use super::convolve::{gen_kernel, ConvolveHandler};
+use super::crop::CropHandler;
+use super::denoise::{gen_denoise, DenoiseHandler};
+use super::effects::{gen_effect, EffectHandler};
+use super::iiif::{QualityHandler, RotateHandler};
+use super::levels::{gen_levels, LevelsHandler};
+use super::liquid::LiquidResizer;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+pub const MAX_OPERATIONS: usize = 16;
+
+/// Keys of the operations below and of the output options of `TransformSpec`, a
+/// `HandlerRegistry` can not replace them
+pub const BUILTIN_KEYS: &[&str] = &[
+    "cr", "w", "h", "s", "lq", "bl", "rt", "q", "cv", "dn", "lv", "fx", "fmt", "jq",
+];
+
+/// One step of a chain, in the order of the URL. In JSON, `{"op": "blur", "value": 3}`.
+#[derive(Debug, Clone, Hash, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", content = "value", rename_all = "snake_case")]
+pub enum Operation {
+    /// `cr:x0y0w500h500`, every part is optional
+    Crop {
+        x: u32,
+        y: u32,
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    /// `w:400`, `h:300` keep the aspect ratio, `s:400x300` does not
+    Resize {
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    /// `lq:400x300`, seam carving, `lq:400x` keeps the aspect ratio
+    Liquid {
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    /// `bl:3`
+    Blur(u32),
+    /// `rt:90`, `rt:!180` mirrors first
+    Rotate(String),
+    /// `q:gray` or `q:bitonal`
+    Quality(String),
+    /// `cv:sharpen`, any kernel of `convolve::gen_kernel`
+    Convolve(String),
+    /// `dn:median2`, any filter of `denoise::gen_denoise`
+    Denoise(String),
+    /// `lv:clahe`, any mode of `levels::gen_levels`
+    Levels(String),
+    /// `fx:shadow,x4,y4`, any effect of `effects::gen_effect`
+    Effect(String),
+    /// Any other key, built by the `HandlerRegistry` of the options
+    Custom { key: String, value: String },
+}
+
+/// Parse the comma separated `key:value` operations of a chain. Commas not followed by a key
+/// belong to the value, e.g. the weights of `cv:k0,-1,0,-1,5,-1,0,-1,0`.
+pub fn gen_operations(chain_s: &str, registry: &HandlerRegistry) -> Result<Vec<Operation>> {
+    let steps = gen_steps(chain_s)?;
+    if steps.len() > MAX_OPERATIONS {
+        return Err(invalid_param(format!(
+            "A chain has at most {} operations",
+            MAX_OPERATIONS
+        )));
+    }
+    steps
+        .iter()
+        .map(|step| gen_operation(step, registry))
+        .collect()
+}
+
+/// Split a chain into its `key:value` steps, an empty chain has none
+pub(crate) fn gen_steps(chain_s: &str) -> Result<Vec<String>> {
+    let mut steps: Vec<String> = Vec::new();
+    if chain_s.is_empty() {
+        return Ok(steps);
+    }
+    for part in chain_s.split(',') {
+        let starts_step = part
+            .find(':')
+            .map(|i| i > 0 && part[..i].chars().all(|c| c.is_ascii_lowercase()))
+            == Some(true);
+        match steps.last_mut() {
+            Some(step) if !starts_step => {
+                step.push(',');
+                step.push_str(part);
+            }
+            None if !starts_step => return Err(invalid_param("Illegal operation")),
+            _ => steps.push(part.to_string()),
+        }
+    }
+    Ok(steps)
+}
+
+pub(crate) fn gen_operation(step_s: &str, registry: &HandlerRegistry) -> Result<Operation> {
+    let (key, value) = step_s.split_at(step_s.find(':').unwrap_or(0));
+    let value = &value[1..];
+    let number = |v: &str| -> Result<u32> {
+        v.parse()
+            .map_err(|_| invalid_param(format!("Illegal operation: {}", step_s)))
+    };
+    let pair = |v: &str| -> Result<(u32, u32)> {
+        let sizes: Vec<&str> = v.split('x').collect();
+        if sizes.len() != 2 {
+            return Err(invalid_param(format!("Illegal operation: {}", step_s)));
+        }
+        Ok((number(sizes[0])?, number(sizes[1])?))
+    };
+    // Check the values now, the handlers are built later
+    let operation = match key {
+        "cr" => {
+            let crop =
+                parse_crop(value).ok_or(invalid_param(format!("Illegal operation: {}", step_s)))?;
+            Operation::Crop {
+                x: crop.0.unwrap_or(0),
+                y: crop.1.unwrap_or(0),
+                width: crop.2,
+                height: crop.3,
+            }
+        }
+        "w" => Operation::Resize {
+            width: Some(number(value)?),
+            height: None,
+        },
+        "h" => Operation::Resize {
+            width: None,
+            height: Some(number(value)?),
+        },
+        "s" => {
+            let (width, height) = pair(value)?;
+            Operation::Resize {
+                width: Some(width),
+                height: Some(height),
+            }
+        }
+        "lq" => {
+            // Either side may be left out, not both
+            let sizes: Vec<&str> = value.split('x').collect();
+            if sizes.len() != 2 || (sizes[0].is_empty() && sizes[1].is_empty()) {
+                return Err(invalid_param(format!("Illegal operation: {}", step_s)));
+            }
+            let side = |v: &str| -> Result<Option<u32>> {
+                if v.is_empty() {
+                    Ok(None)
+                } else {
+                    number(v).map(Some)
+                }
+            };
+            Operation::Liquid {
+                width: side(sizes[0])?,
+                height: side(sizes[1])?,
+            }
+        }
+        "bl" => Operation::Blur(number(value)?),
+        "rt" => {
+            RotateHandler::parse(value)?;
+            Operation::Rotate(value.to_string())
+        }
+        "q" => {
+            QualityHandler::parse(value)?;
+            Operation::Quality(value.to_string())
+        }
+        "cv" => {
+            gen_kernel(value)?;
+            Operation::Convolve(value.to_string())
+        }
+        "dn" => {
+            gen_denoise(value, None)?;
+            Operation::Denoise(value.to_string())
+        }
+        "lv" => {
+            gen_levels(value)?;
+            Operation::Levels(value.to_string())
+        }
+        "fx" => {
+            gen_effect(value)?;
+            Operation::Effect(value.to_string())
+        }
+        _ => {
+            registry.build(key, value)?;
+            Operation::Custom {
+                key: key.to_string(),
+                value: value.to_string(),
+            }
+        }
+    };
+    Ok(operation)
+}
+
+/// `x`, `y`, `w` and `h` followed by their values, in any order
+fn parse_crop(crop_s: &str) -> Option<(Option<u32>, Option<u32>, Option<u32>, Option<u32>)> {
+    if !crop_s.is_ascii() {
+        return None;
+    }
+    let mut crop = (None, None, None, None);
+    let mut rest = crop_s;
+    while !rest.is_empty() {
+        let key = rest.chars().next()?;
+        let digits = rest[1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map(|i| i + 1)
+            .unwrap_or_else(|| rest.len());
+        let value = rest[1..digits].parse().ok()?;
+        match key {
+            'x' => crop.0 = Some(value),
+            'y' => crop.1 = Some(value),
+            'w' => crop.2 = Some(value),
+            'h' => crop.3 = Some(value),
+            _ => return None,
+        }
+        rest = &rest[digits..];
+    }
+    Some(crop)
+}
+
+/// The step of the operation in a chain, parsed back by `gen_operations`
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = |v: &Option<u32>| v.map(|v| v.to_string()).unwrap_or_default();
+        match self {
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                write!(f, "cr:x{}y{}", x, y)?;
+                if let Some(width) = width {
+                    write!(f, "w{}", width)?;
+                }
+                if let Some(height) = height {
+                    write!(f, "h{}", height)?;
+                }
+                Ok(())
+            }
+            Operation::Resize {
+                width: Some(width),
+                height: None,
+            } => write!(f, "w:{}", width),
+            Operation::Resize {
+                width: None,
+                height: Some(height),
+            } => write!(f, "h:{}", height),
+            Operation::Resize { width, height } => {
+                write!(f, "s:{}x{}", size(width), size(height))
+            }
+            Operation::Liquid { width, height } => {
+                write!(f, "lq:{}x{}", size(width), size(height))
+            }
+            Operation::Blur(level) => write!(f, "bl:{}", level),
+            Operation::Rotate(rotation_s) => write!(f, "rt:{}", rotation_s),
+            Operation::Quality(quality_s) => write!(f, "q:{}", quality_s),
+            Operation::Convolve(kernel_s) => write!(f, "cv:{}", kernel_s),
+            Operation::Denoise(denoise_s) => write!(f, "dn:{}", denoise_s),
+            Operation::Levels(levels_s) => write!(f, "lv:{}", levels_s),
+            Operation::Effect(effect_s) => write!(f, "fx:{}", effect_s),
+            Operation::Custom { key, value } => write!(f, "{}:{}", key, value),
+        }
+    }
+}
+
+impl Operation {
+    pub(crate) fn handler(
+        &self,
+        opts: &ImageOption,
+        img_info: &ImageInfo,
+    ) -> Result<Box<ImageHandler>> {
+        Ok(match self {
+            Operation::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => Box::new(CropHandler::new(
+                *x,
+                *y,
+                width.unwrap_or(MAX),
+                height.unwrap_or(MAX),
+            )),
+            Operation::Resize { width, height } => Box::new(Resizer {
+                width: *width,
+                height: *height,
+                filter_type: opts.filter_type(),
+                linear: img_info.linear_light.unwrap_or(opts.linear_light()),
+            }),
+            Operation::Liquid { width, height } => {
+                Box::new(LiquidResizer::new(*width, *height, opts.filter_type()))
+            }
+            Operation::Blur(level) => Box::new(BlurHandler::new(Some(*level))),
+            Operation::Rotate(rotation_s) => Box::new(RotateHandler::parse(rotation_s)?),
+            Operation::Quality(quality_s) => Box::new(QualityHandler::parse(quality_s)?),
+            Operation::Convolve(kernel_s) => Box::new(ConvolveHandler::new(gen_kernel(kernel_s)?)),
+            Operation::Denoise(denoise_s) => {
+                Box::new(DenoiseHandler::new(gen_denoise(denoise_s, None)?))
+            }
+            Operation::Levels(levels_s) => Box::new(LevelsHandler::new(gen_levels(levels_s)?)),
+            Operation::Effect(effect_s) => Box::new(EffectHandler::new(gen_effect(effect_s)?)),
+            Operation::Custom { key, value } => opts.handler_registry().build(key, value)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chain() {
+        let registry = HandlerRegistry::new();
+        let chain_s = "cr:x0y0w500h500,w:400,bl:3,rt:90,cv:k0,-1,0,-1,5,-1,0,-1,0";
+        let operations = gen_operations(chain_s, &registry).unwrap();
+        assert_eq!(operations.len(), 5);
+        let steps: Vec<String> = operations.iter().map(|op| op.to_string()).collect();
+        assert_eq!(steps.join(","), chain_s);
+        assert_eq!(
+            operations[0],
+            Operation::Crop {
+                x: 0,
+                y: 0,
+                width: Some(500),
+                height: Some(500),
+            }
+        );
+        assert_eq!(
+            operations[4],
+            Operation::Convolve("k0,-1,0,-1,5,-1,0,-1,0".to_string())
+        );
+        assert!(gen_operations("x0y0", &registry).is_err());
+        assert!(gen_operations("rt:45", &registry).is_err());
+        assert!(gen_operations("cr:x0z1", &registry).is_err());
+        assert!(gen_operations("px:8", &registry).is_err());
+        assert!(gen_operations("lq:x", &registry).is_err());
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let mut img_info = ImageInfo::new("ferris", "png", None, None);
+        img_info.transform(
+            TransformSpec::from_path("cr:x100y50w600,w:300,fx:shadow,x4,y4,rt:!90", &registry)
+                .unwrap(),
+        );
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+    }
+}
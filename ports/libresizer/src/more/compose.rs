@@ -0,0 +1,203 @@
+use super::super::*;
+use image::{imageops, ImageBuffer, Rgba, RgbaImage};
+
+pub const MAX_CANVAS: u32 = 4096;
+pub const MAX_CELLS: usize = 64;
+
+/// An original drawn into a rectangle of the canvas
+#[derive(Debug, Clone, Hash, PartialEq)]
+pub struct Cell {
+    pub fname: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where the originals go on the canvas, the later cells are drawn over the earlier ones
+#[derive(Debug, Clone, Hash, PartialEq)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    pub background: [u8; 4],
+    pub cells: Vec<Cell>,
+}
+
+impl Layout {
+    pub fn new(width: u32, height: u32, background: [u8; 4], cells: Vec<Cell>) -> Result<Self> {
+        if width == 0 || height == 0 || width > MAX_CANVAS || height > MAX_CANVAS {
+            return Err(invalid_param(format!(
+                "The canvas must be between 1 and {} pixels wide and high",
+                MAX_CANVAS
+            )));
+        }
+        if cells.is_empty() || cells.len() > MAX_CELLS {
+            return Err(invalid_param(format!(
+                "A layout has between 1 and {} images",
+                MAX_CELLS
+            )));
+        }
+        for cell in &cells {
+            check_fname(&cell.fname)?;
+            if cell.width == 0
+                || cell.height == 0
+                || u64::from(cell.x) + u64::from(cell.width) > u64::from(width)
+                || u64::from(cell.y) + u64::from(cell.height) > u64::from(height)
+            {
+                return Err(invalid_param(format!(
+                    "The cell of {} is outside of the canvas",
+                    cell.fname
+                )));
+            }
+        }
+        Ok(Layout {
+            width,
+            height,
+            background,
+            cells,
+        })
+    }
+
+    /// Cells of the same size, row by row, `gap` pixels apart and around
+    pub fn grid(
+        fnames: &[String],
+        columns: u32,
+        cell_width: u32,
+        cell_height: u32,
+        gap: u32,
+        background: [u8; 4],
+    ) -> Result<Self> {
+        if columns == 0 {
+            return Err(invalid_param("A grid has at least one column"));
+        }
+        let count = fnames.len() as u32;
+        let columns = columns.min(count.max(1));
+        let rows = (count + columns - 1) / columns;
+        let span =
+            |n: u32, size: u32| u64::from(n) * (u64::from(size) + u64::from(gap)) + u64::from(gap);
+        let (width, height) = (span(columns, cell_width), span(rows, cell_height));
+        if width > u64::from(MAX_CANVAS) || height > u64::from(MAX_CANVAS) {
+            return Err(invalid_param(format!(
+                "The canvas must be between 1 and {} pixels wide and high",
+                MAX_CANVAS
+            )));
+        }
+        let cells = fnames
+            .iter()
+            .enumerate()
+            .map(|(i, fname)| {
+                let (column, row) = (i as u32 % columns, i as u32 / columns);
+                Cell {
+                    fname: fname.clone(),
+                    x: gap + column * (cell_width + gap),
+                    y: gap + row * (cell_height + gap),
+                    width: cell_width,
+                    height: cell_height,
+                }
+            })
+            .collect();
+        Layout::new(width as u32, height as u32, background, cells)
+    }
+}
+
+/// Names come from the requests, they must stay in the originals directory
+pub fn check_fname(fname: &str) -> Result<()> {
+    if fname.contains('/') || fname.contains('\\') || fname.starts_with('.') {
+        Err(invalid_param(format!("Illegal image name: {}", fname)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse `x,y,w,h` rectangles separated by semicolons, one per original
+pub fn gen_cells(fnames: &[String], cells_s: &str) -> Result<Vec<Cell>> {
+    let rects: Vec<&str> = cells_s.split(';').filter(|r| !r.is_empty()).collect();
+    if rects.len() != fnames.len() {
+        return Err(invalid_param("One cell is needed for each image"));
+    }
+    fnames
+        .iter()
+        .zip(rects)
+        .map(|(fname, rect_s)| {
+            let rect = rect_s
+                .split(',')
+                .map(|v| v.parse::<u32>())
+                .collect::<std::result::Result<Vec<u32>, _>>()
+                .map_err(|_| invalid_param("Illegal cell"))?;
+            if rect.len() != 4 {
+                return Err(invalid_param("Cells are made of x,y,w,h"));
+            }
+            Ok(Cell {
+                fname: fname.clone(),
+                x: rect[0],
+                y: rect[1],
+                width: rect[2],
+                height: rect[3],
+            })
+        })
+        .collect()
+}
+
+/// Scale to cover the cell, cropping the overflow around the center
+fn cover(img: &DynamicImage, width: u32, height: u32, filter_type: FilterType) -> RgbaImage {
+    let (w, h) = img.dimensions();
+    let scale = f64::max(
+        f64::from(width) / f64::from(w),
+        f64::from(height) / f64::from(h),
+    );
+    let sw = ((f64::from(w) * scale).ceil() as u32).max(width);
+    let sh = ((f64::from(h) * scale).ceil() as u32).max(height);
+    let mut scaled = imageops::resize(img, sw, sh, filter_type);
+    imageops::crop(
+        &mut scaled,
+        (sw - width) / 2,
+        (sh - height) / 2,
+        width,
+        height,
+    )
+    .to_image()
+}
+
+/// Draw the originals of the layout and save the result like the other outputs
+pub fn compose(opts: &ImageOption, layout: &Layout, format_s: &str) -> Result<u64> {
+    let mut canvas: RgbaImage = ImageBuffer::from_pixel(
+        layout.width,
+        layout.height,
+        Rgba {
+            data: layout.background,
+        },
+    );
+    for cell in &layout.cells {
+        let mut fpath = PathBuf::from(&opts.input_dir());
+        fpath.push(&cell.fname);
+        let img = image::load_from_memory(&std::fs::read(&fpath)?)?;
+        let fitted = cover(&img, cell.width, cell.height, opts.filter_type());
+        imageops::overlay(&mut canvas, &fitted, cell.x, cell.y);
+    }
+    let result_img = DynamicImage::ImageRgba8(canvas);
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&result_img.raw_pixels());
+    let mut encoded = Vec::new();
+    result_img.write_to(&mut encoded, output_format(format_s)?)?;
+    save_output(opts, hasher, Metadata::default(), encoded, format_s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compose() {
+        let fnames = vec!["ferris.png".to_string(); 3];
+        let grid = Layout::grid(&fnames, 2, 100, 80, 4, [255, 255, 255, 255]).unwrap();
+        assert_eq!((grid.width, grid.height), (212, 172));
+        assert_eq!((grid.cells[2].x, grid.cells[2].y), (4, 88));
+        assert!(gen_cells(&fnames, "0,0,10,10;10,0,10,10").is_err());
+        let cells = gen_cells(&fnames, "0,0,200,200;200,0,100,100;200,100,100,100").unwrap();
+        assert!(Layout::new(250, 200, [0, 0, 0, 0], cells.clone()).is_err());
+        let layout = Layout::new(300, 200, [0, 0, 0, 0], cells).unwrap();
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        println!("hash: {}", compose(&opts, &grid, "jpg").unwrap());
+        println!("hash: {}", compose(&opts, &layout, "png").unwrap());
+    }
+}
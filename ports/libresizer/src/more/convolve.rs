@@ -0,0 +1,198 @@
+use super::super::*;
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Kernel {
+    /// A 3x3 or 5x5 matrix, the weighted sum is divided by `divisor`
+    Matrix {
+        size: u32,
+        weights: Vec<f32>,
+        divisor: f32,
+    },
+    /// Gradient magnitude of the Sobel operators, as a grayscale image
+    Sobel,
+}
+
+/// Parse a preset name or a custom kernel of 9 or 25 comma separated weights prefixed by `k`,
+/// e.g. `k0,-1,0,-1,5,-1,0,-1,0`. Custom kernels are divided by the sum of their weights.
+pub fn gen_kernel(kernel_s: &str) -> Result<Kernel> {
+    let matrix = |weights: &[f32], divisor: f32| Kernel::Matrix {
+        size: (weights.len() as f64).sqrt() as u32,
+        weights: weights.to_vec(),
+        divisor,
+    };
+    match kernel_s.to_lowercase().as_str() {
+        "edge" => Ok(matrix(
+            &[-1.0, -1.0, -1.0, -1.0, 8.0, -1.0, -1.0, -1.0, -1.0],
+            1.0,
+        )),
+        "emboss" => Ok(matrix(
+            &[-2.0, -1.0, 0.0, -1.0, 1.0, 1.0, 0.0, 1.0, 2.0],
+            1.0,
+        )),
+        "sharpen" => Ok(matrix(
+            &[0.0, -1.0, 0.0, -1.0, 5.0, -1.0, 0.0, -1.0, 0.0],
+            1.0,
+        )),
+        "box" => Ok(matrix(&[1.0; 9], 9.0)),
+        "box5" => Ok(matrix(&[1.0; 25], 25.0)),
+        "gaussian5" => {
+            let row = [1.0, 4.0, 6.0, 4.0, 1.0];
+            let weights: Vec<f32> = row
+                .iter()
+                .flat_map(|a| row.iter().map(move |b| a * b))
+                .collect();
+            Ok(matrix(&weights, 256.0))
+        }
+        "sobel" => Ok(Kernel::Sobel),
+        custom if custom.starts_with('k') => {
+            let weights = custom[1..]
+                .split(',')
+                .map(|w| w.parse::<f32>())
+                .collect::<std::result::Result<Vec<f32>, _>>()
+                .map_err(|_| invalid_param("Illegal kernel weights"))?;
+            if weights.len() != 9 && weights.len() != 25 {
+                return Err(invalid_param("Kernels must have 9 or 25 weights"));
+            }
+            let sum: f32 = weights.iter().sum();
+            // Zero-sum kernels (edges) are left unnormalized
+            let divisor = if sum.abs() < std::f32::EPSILON {
+                1.0
+            } else {
+                sum
+            };
+            Ok(matrix(&weights, divisor))
+        }
+        _ => Err(invalid_param("Unknown kernel")),
+    }
+}
+
+pub struct ConvolveHandler {
+    kernel: Kernel,
+}
+
+impl ConvolveHandler {
+    pub fn new(kernel: Kernel) -> Self {
+        ConvolveHandler { kernel }
+    }
+}
+
+impl ImageHandler for ConvolveHandler {
+    fn name(&self) -> &str {
+        "convolve"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{:?}", self.kernel)
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        let has_alpha = match img {
+            DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+            _ => false,
+        };
+        let rgba = img.to_rgba();
+        let result = match &self.kernel {
+            Kernel::Matrix {
+                size,
+                weights,
+                divisor,
+            } => convolve_rgba(&rgba, *size, weights, *divisor),
+            Kernel::Sobel => {
+                return Ok(DynamicImage::ImageLuma8(sobel(&rgba)));
+            }
+        };
+        let result = DynamicImage::ImageRgba8(result);
+        if has_alpha {
+            Ok(result)
+        } else {
+            Ok(DynamicImage::ImageRgb8(result.to_rgb()))
+        }
+    }
+}
+
+/// Convolve the colour channels, edges repeat the border pixels and alpha is kept
+fn convolve_rgba(img: &RgbaImage, size: u32, weights: &[f32], divisor: f32) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let radius = (size / 2) as i64;
+    let clamp = |v: i64, max: u32| v.max(0).min(i64::from(max) - 1) as u32;
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let mut sum = [0f32; 3];
+        for ky in 0..size {
+            for kx in 0..size {
+                let sx = clamp(i64::from(x) + i64::from(kx) - radius, width);
+                let sy = clamp(i64::from(y) + i64::from(ky) - radius, height);
+                let p = img.get_pixel(sx, sy).data;
+                let weight = weights[(ky * size + kx) as usize];
+                for c in 0..3 {
+                    sum[c] += f32::from(p[c]) * weight;
+                }
+            }
+        }
+        let channel = |v: f32| (v / divisor).round().max(0.0).min(255.0) as u8;
+        Rgba {
+            data: [
+                channel(sum[0]),
+                channel(sum[1]),
+                channel(sum[2]),
+                img.get_pixel(x, y).data[3],
+            ],
+        }
+    })
+}
+
+fn sobel(img: &RgbaImage) -> image::GrayImage {
+    let gray = DynamicImage::ImageRgba8(img.clone()).to_luma();
+    let (width, height) = gray.dimensions();
+    let at = |x: i64, y: i64| {
+        let x = x.max(0).min(i64::from(width) - 1) as u32;
+        let y = y.max(0).min(i64::from(height) - 1) as u32;
+        f32::from(gray.get_pixel(x, y).data[0])
+    };
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let (x, y) = (i64::from(x), i64::from(y));
+        let gx = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+            - at(x - 1, y - 1)
+            - 2.0 * at(x - 1, y)
+            - at(x - 1, y + 1);
+        let gy = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+            - at(x - 1, y - 1)
+            - 2.0 * at(x, y - 1)
+            - at(x + 1, y - 1);
+        image::Luma {
+            data: [(gx * gx + gy * gy).sqrt().min(255.0) as u8],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convolve() {
+        assert!(gen_kernel("k1,2,1,2,4,2,1,2,1").is_ok());
+        assert!(gen_kernel("k1,2,1").is_err());
+        assert!(gen_kernel("nope").is_err());
+        // An edge kernel turns flat areas black
+        let flat = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(
+            6,
+            6,
+            image::Rgb {
+                data: [120, 80, 40],
+            },
+        ));
+        let edges = ConvolveHandler::new(gen_kernel("edge").unwrap())
+            .handle(flat.clone())
+            .unwrap();
+        assert_eq!(edges.to_rgb().get_pixel(3, 3).data, [0, 0, 0]);
+        let boxed = ConvolveHandler::new(gen_kernel("box5").unwrap())
+            .handle(flat)
+            .unwrap();
+        assert_eq!(boxed.to_rgb().get_pixel(0, 0).data, [120, 80, 40]);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let mut img_info = ImageInfo::new("ferris", "png", Some(400), None);
+        img_info.kernel("sobel");
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+    }
+}
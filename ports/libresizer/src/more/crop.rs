@@ -1,8 +1,7 @@
 use super::super::*;
-use super::blur::BlurHandler;
 use image::DynamicImage;
 
-struct CropHandler {
+pub struct CropHandler {
     x: u32,
     y: u32,
     width: u32,
@@ -10,7 +9,7 @@ struct CropHandler {
 }
 
 impl CropHandler {
-    fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
         CropHandler {
             x,
             y,
@@ -21,31 +20,32 @@ impl CropHandler {
 }
 
 impl ImageHandler for CropHandler {
+    fn name(&self) -> &str {
+        "crop"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+
     fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
         let mut nimg = img.clone();
         Ok(nimg.crop(self.x, self.y, self.width, self.height))
     }
+
+    fn handle_deep(&self, mut img: Rgba16Image) -> Result<Rgba16Image> {
+        Ok(image::imageops::crop(&mut img, self.x, self.y, self.width, self.height).to_image())
+    }
+
+    fn supports_deep(&self) -> bool {
+        true
+    }
 }
 
+/// Crop, resize then blur, as set by `ImageInfo::crop`, `ImageInfo::new` and `ImageInfo::blur`
+#[deprecated(note = "the operations of `ImageInfo` all run with `resize`")]
 pub fn crop(opts: &ImageOption, img_info: &ImageInfo) -> Result<u64> {
-    let resizer = Resizer {
-        width: img_info.width,
-        height: img_info.height,
-        filter_type: opts.filter_type(),
-    };
-    let crop_handler = CropHandler::new(
-        img_info.crop_x.unwrap_or(0),
-        img_info.crop_y.unwrap_or(0),
-        img_info.crop_w.unwrap_or(MAX),
-        img_info.crop_h.unwrap_or(MAX),
-    );
-    let blur_handler = BlurHandler::new(img_info.blur_level());
-    let handlers: Vec<&ImageHandler> = if let Some(_blur) = img_info.blur_level() {
-        vec![&crop_handler, &resizer, &blur_handler]
-    } else {
-        vec![&crop_handler, &resizer]
-    };
-    pipeline(opts, img_info, handlers)
+    resize(opts, img_info)
 }
 
 #[cfg(test)]
@@ -54,6 +54,7 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(deprecated)]
     fn test_crop() {
         let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
         let mut img_info = ImageInfo::new("ferris", "png", None, None);
@@ -61,4 +62,12 @@ mod tests {
         img_info.blur(15);
         println!("hash: {}", crop(&opts, &img_info).unwrap());
     }
+
+    #[test]
+    fn test_crop_spec() {
+        let mut img_info = ImageInfo::new("ferris", "png", None, None);
+        img_info.crop(Some(500), None, None, Some(200));
+        img_info.blur(15);
+        assert_eq!(img_info.transform_spec().to_path(), "cr:x500y0h200,bl:15");
+    }
 }
@@ -0,0 +1,252 @@
+use super::super::*;
+use image::{ImageBuffer, Rgba, RgbaImage};
+use std::fmt;
+
+pub const MAX_RADIUS: u32 = 5;
+const DEFAULT_SIGMA_COLOR: u32 = 25;
+
+/// Noise reduction applied to the original, before any resizing
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum Denoise {
+    /// Median of the `(2r + 1)²` neighbourhood
+    Median(u32),
+    /// Edge-preserving average, pixels differing by much more than `sigma_color` barely count
+    Bilateral { radius: u32, sigma_color: u32 },
+}
+
+/// Parse `median{r}` or `bilateral{r}`, the radius defaults to 1. The sigma of a bilateral
+/// filter is `sigma_color` unless given after the radius, e.g. `bilateral2s30`.
+pub fn gen_denoise(denoise_s: &str, sigma_color: Option<u32>) -> Result<Denoise> {
+    let denoise_s = denoise_s.to_lowercase();
+    let radius_of = |radius_s: &str| -> Result<u32> {
+        let radius = if radius_s.is_empty() {
+            1
+        } else {
+            radius_s
+                .parse()
+                .map_err(|_| invalid_param("Illegal denoise radius"))?
+        };
+        if radius < 1 || radius > MAX_RADIUS {
+            return Err(invalid_param(format!(
+                "Denoise radius must be between 1 and {}",
+                MAX_RADIUS
+            )));
+        }
+        Ok(radius)
+    };
+    if denoise_s.starts_with("median") {
+        Ok(Denoise::Median(radius_of(&denoise_s["median".len()..])?))
+    } else if denoise_s.starts_with("bilateral") {
+        let params_s = &denoise_s["bilateral".len()..];
+        let (radius_s, sigma_color) = match params_s.find('s') {
+            Some(i) => (
+                &params_s[..i],
+                params_s[i + 1..]
+                    .parse()
+                    .map_err(|_| invalid_param("Illegal bilateral sigma"))?,
+            ),
+            None => (params_s, sigma_color.unwrap_or(DEFAULT_SIGMA_COLOR)),
+        };
+        if sigma_color == 0 {
+            return Err(invalid_param("Bilateral sigma must not be zero"));
+        }
+        Ok(Denoise::Bilateral {
+            radius: radius_of(radius_s)?,
+            sigma_color,
+        })
+    } else {
+        Err(invalid_param("Unknown denoise filter"))
+    }
+}
+
+/// The filter in the syntax of `gen_denoise`
+impl fmt::Display for Denoise {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Denoise::Median(radius) => write!(f, "median{}", radius),
+            Denoise::Bilateral {
+                radius,
+                sigma_color,
+            } => write!(f, "bilateral{}s{}", radius, sigma_color),
+        }
+    }
+}
+
+pub struct DenoiseHandler {
+    denoise: Denoise,
+}
+
+impl DenoiseHandler {
+    pub fn new(denoise: Denoise) -> Self {
+        DenoiseHandler { denoise }
+    }
+}
+
+impl ImageHandler for DenoiseHandler {
+    fn name(&self) -> &str {
+        "denoise"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{:?}", self.denoise)
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        let has_alpha = match img {
+            DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+            _ => false,
+        };
+        let rgba = img.to_rgba();
+        let result = DynamicImage::ImageRgba8(match self.denoise {
+            Denoise::Median(radius) => median(&rgba, radius),
+            Denoise::Bilateral {
+                radius,
+                sigma_color,
+            } => bilateral(&rgba, radius, sigma_color),
+        });
+        if has_alpha {
+            Ok(result)
+        } else {
+            Ok(DynamicImage::ImageRgb8(result.to_rgb()))
+        }
+    }
+}
+
+/// Huang's algorithm, sliding a histogram per channel along each row
+fn median(img: &RgbaImage, radius: u32) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let r = radius as i64;
+    let clamp = |v: i64, max: u32| v.max(0).min(i64::from(max) - 1) as u32;
+    let window = ((2 * r + 1) * (2 * r + 1)) as u32;
+    let mut out = ImageBuffer::new(width, height);
+    for y in 0..height {
+        let mut histograms = [[0u32; 256]; 3];
+        let column = |hist: &mut [[u32; 256]; 3], x: i64, add: bool| {
+            for dy in -r..=r {
+                let p = img.get_pixel(clamp(x, width), clamp(i64::from(y) + dy, height));
+                for c in 0..3 {
+                    let bin = &mut hist[c][p.data[c] as usize];
+                    if add {
+                        *bin += 1;
+                    } else {
+                        *bin -= 1;
+                    }
+                }
+            }
+        };
+        for dx in -r..=r {
+            column(&mut histograms, dx, true);
+        }
+        for x in 0..width {
+            if x > 0 {
+                column(&mut histograms, i64::from(x) - r - 1, false);
+                column(&mut histograms, i64::from(x) + r, true);
+            }
+            let mut data = [0, 0, 0, img.get_pixel(x, y).data[3]];
+            for c in 0..3 {
+                let mut count = 0;
+                for (value, n) in histograms[c].iter().enumerate() {
+                    count += n;
+                    if count * 2 > window {
+                        data[c] = value as u8;
+                        break;
+                    }
+                }
+            }
+            out.put_pixel(x, y, Rgba { data });
+        }
+    }
+    out
+}
+
+fn bilateral(img: &RgbaImage, radius: u32, sigma_color: u32) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let r = radius as i64;
+    let clamp = |v: i64, max: u32| v.max(0).min(i64::from(max) - 1) as u32;
+    // The spatial sigma follows the radius, the window covering about 2 sigmas
+    let sigma_space = (radius as f32 / 2.0).max(0.5);
+    let spatial: Vec<f32> = (-r..=r)
+        .flat_map(|dy| {
+            (-r..=r).map(move |dx| {
+                (-((dx * dx + dy * dy) as f32) / (2.0 * sigma_space * sigma_space)).exp()
+            })
+        })
+        .collect();
+    // Indexed by the sum of the absolute channel differences
+    let sigma = sigma_color as f32 * 3.0;
+    let range: Vec<f32> = (0..=765)
+        .map(|d| (-((d * d) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let center = img.get_pixel(x, y).data;
+        let mut sum = [0f32; 3];
+        let mut total = 0.0;
+        let mut k = 0;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let p = img
+                    .get_pixel(
+                        clamp(i64::from(x) + dx, width),
+                        clamp(i64::from(y) + dy, height),
+                    )
+                    .data;
+                let diff: i32 = (0..3)
+                    .map(|c| (i32::from(p[c]) - i32::from(center[c])).abs())
+                    .sum();
+                let weight = spatial[k] * range[diff as usize];
+                for c in 0..3 {
+                    sum[c] += f32::from(p[c]) * weight;
+                }
+                total += weight;
+                k += 1;
+            }
+        }
+        let channel = |v: f32| (v / total).round().max(0.0).min(255.0) as u8;
+        Rgba {
+            data: [channel(sum[0]), channel(sum[1]), channel(sum[2]), center[3]],
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_denoise() {
+        assert_eq!(gen_denoise("median", None).unwrap(), Denoise::Median(1));
+        assert!(gen_denoise("median9", None).is_err());
+        let bilateral = gen_denoise("bilateral2", Some(30)).unwrap();
+        assert_eq!(
+            gen_denoise(&bilateral.to_string(), None).unwrap(),
+            bilateral
+        );
+        // A single hot pixel disappears with the median, the bilateral filter keeps edges
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(9, 9, |x, y| {
+            if x == 4 && y == 4 {
+                image::Rgb {
+                    data: [255, 255, 255],
+                }
+            } else if x < 2 {
+                image::Rgb { data: [200, 0, 0] }
+            } else {
+                image::Rgb { data: [0, 0, 0] }
+            }
+        }));
+        let median = DenoiseHandler::new(gen_denoise("median1", None).unwrap())
+            .handle(img.clone())
+            .unwrap()
+            .to_rgb();
+        assert_eq!(median.get_pixel(4, 4).data, [0, 0, 0]);
+        let bilateral = DenoiseHandler::new(gen_denoise("bilateral2", Some(10)).unwrap())
+            .handle(img)
+            .unwrap()
+            .to_rgb();
+        assert_eq!(bilateral.get_pixel(1, 0).data, [200, 0, 0]);
+        assert_eq!(bilateral.get_pixel(2, 0).data, [0, 0, 0]);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let mut img_info = ImageInfo::new("ferris", "png", Some(300), None);
+        img_info.denoise(Denoise::Median(2));
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+    }
+}
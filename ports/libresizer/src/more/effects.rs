@@ -0,0 +1,306 @@
+use super::super::*;
+use image::{imageops, ImageBuffer, Rgba, RgbaImage};
+use std::collections::HashMap;
+use std::fmt;
+
+const MAX_OFFSET: i32 = 100;
+const MAX_BLUR: u32 = 50;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum Effect {
+    /// Darken the corners, by up to `strength` percent
+    Vignette { strength: u32 },
+    /// Blurred copy of the silhouette under the image, the canvas grows to fit it
+    Shadow {
+        offset_x: i32,
+        offset_y: i32,
+        blur: u32,
+        color: [u8; 4],
+    },
+    /// Blurred halo around the silhouette, the canvas grows to fit it
+    Glow { radius: u32, color: [u8; 4] },
+}
+
+/// Parse an effect name followed by comma separated parameters, a letter then a value:
+/// `vignette,s50`, `shadow,x4,y4,b8,c00000080` or `glow,r8,cffffffc0`
+pub fn gen_effect(effect_s: &str) -> Result<Effect> {
+    let effect_s = effect_s.to_lowercase();
+    let mut fields = effect_s.split(',');
+    let name = fields.next().unwrap_or("");
+    let mut params = HashMap::new();
+    for field in fields {
+        if field.len() < 2 {
+            return Err(invalid_param("Illegal effect parameter"));
+        }
+        params.insert(&field[..1], &field[1..]);
+    }
+    let number = |key: &str, default: i32, min: i32, max: i32| -> Result<i32> {
+        let value = match params.get(key) {
+            Some(value_s) => value_s
+                .parse()
+                .map_err(|_| invalid_param(format!("Illegal effect parameter: {}", key)))?,
+            None => default,
+        };
+        if value < min || value > max {
+            return Err(invalid_param(format!(
+                "Effect parameter {} must be between {} and {}",
+                key, min, max
+            )));
+        }
+        Ok(value)
+    };
+    let color = |default: [u8; 4]| -> Result<[u8; 4]> {
+        match params.get("c") {
+            Some(color_s) => gen_color(color_s),
+            None => Ok(default),
+        }
+    };
+    match name {
+        "vignette" => Ok(Effect::Vignette {
+            strength: number("s", 50, 0, 100)? as u32,
+        }),
+        "shadow" => Ok(Effect::Shadow {
+            offset_x: number("x", 4, -MAX_OFFSET, MAX_OFFSET)?,
+            offset_y: number("y", 4, -MAX_OFFSET, MAX_OFFSET)?,
+            blur: number("b", 8, 0, MAX_BLUR as i32)? as u32,
+            color: color([0, 0, 0, 0x80])?,
+        }),
+        "glow" => Ok(Effect::Glow {
+            radius: number("r", 8, 1, MAX_BLUR as i32)? as u32,
+            color: color([0xff, 0xff, 0xff, 0xc0])?,
+        }),
+        _ => Err(invalid_param("Unknown effect")),
+    }
+}
+
+/// `rrggbb` or `rrggbbaa`
+pub fn gen_color(color_s: &str) -> Result<[u8; 4]> {
+    if (color_s.len() != 6 && color_s.len() != 8) || !color_s.is_ascii() {
+        return Err(invalid_param("Illegal colour"));
+    }
+    let mut color = [0, 0, 0, 0xff];
+    for (i, c) in color.iter_mut().enumerate().take(color_s.len() / 2) {
+        *c = u8::from_str_radix(&color_s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| invalid_param("Illegal colour"))?;
+    }
+    Ok(color)
+}
+
+/// The effect in the syntax of `gen_effect`
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex = |c: &[u8; 4]| format!("{:02x}{:02x}{:02x}{:02x}", c[0], c[1], c[2], c[3]);
+        match self {
+            Effect::Vignette { strength } => write!(f, "vignette,s{}", strength),
+            Effect::Shadow {
+                offset_x,
+                offset_y,
+                blur,
+                color,
+            } => write!(
+                f,
+                "shadow,x{},y{},b{},c{}",
+                offset_x,
+                offset_y,
+                blur,
+                hex(color)
+            ),
+            Effect::Glow { radius, color } => write!(f, "glow,r{},c{}", radius, hex(color)),
+        }
+    }
+}
+
+pub struct EffectHandler {
+    effect: Effect,
+}
+
+impl EffectHandler {
+    pub fn new(effect: Effect) -> Self {
+        EffectHandler { effect }
+    }
+}
+
+impl ImageHandler for EffectHandler {
+    fn name(&self) -> &str {
+        "effect"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{:?}", self.effect)
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        match self.effect {
+            Effect::Vignette { strength } => {
+                let has_alpha = match img {
+                    DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+                    _ => false,
+                };
+                let result = DynamicImage::ImageRgba8(vignette(img.to_rgba(), strength));
+                if has_alpha {
+                    Ok(result)
+                } else {
+                    Ok(DynamicImage::ImageRgb8(result.to_rgb()))
+                }
+            }
+            // The expanded canvas is transparent, the result always has an alpha channel
+            Effect::Shadow {
+                offset_x,
+                offset_y,
+                blur,
+                color,
+            } => Ok(DynamicImage::ImageRgba8(halo(
+                &img.to_rgba(),
+                offset_x,
+                offset_y,
+                blur as f32 / 2.0,
+                color,
+                1.0,
+            ))),
+            Effect::Glow { radius, color } => Ok(DynamicImage::ImageRgba8(halo(
+                &img.to_rgba(),
+                0,
+                0,
+                radius as f32 / 2.0,
+                color,
+                // Stronger than a shadow, the halo has to stand out of the blur
+                2.0,
+            ))),
+        }
+    }
+}
+
+fn vignette(mut img: RgbaImage, strength: u32) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let (cx, cy) = (f64::from(width) / 2.0, f64::from(height) / 2.0);
+    let strength = f64::from(strength) / 100.0;
+    for (x, y, p) in img.enumerate_pixels_mut() {
+        // Elliptical distance to the center, 1 in the corners
+        let dx = (f64::from(x) + 0.5 - cx) / cx;
+        let dy = (f64::from(y) + 0.5 - cy) / cy;
+        let d = (dx * dx + dy * dy) / 2.0;
+        let factor = 1.0 - strength * d * d;
+        for c in 0..3 {
+            p.data[c] = (f64::from(p.data[c]) * factor).round().max(0.0) as u8;
+        }
+    }
+    img
+}
+
+/// Draw the image over a blurred, tinted copy of its alpha channel
+fn halo(
+    img: &RgbaImage,
+    offset_x: i32,
+    offset_y: i32,
+    sigma: f32,
+    color: [u8; 4],
+    gain: f32,
+) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    // The blur fades out within three sigmas
+    let spread = (sigma * 3.0).ceil() as i32;
+    let left = (spread - offset_x).max(0) as u32;
+    let right = (spread + offset_x).max(0) as u32;
+    let top = (spread - offset_y).max(0) as u32;
+    let bottom = (spread + offset_y).max(0) as u32;
+    let (canvas_w, canvas_h) = (width + left + right, height + top + bottom);
+    let mut mask = ImageBuffer::from_pixel(
+        canvas_w,
+        canvas_h,
+        Rgba {
+            data: [color[0], color[1], color[2], 0],
+        },
+    );
+    let (sx, sy) = (
+        (left as i32 + offset_x) as u32,
+        (top as i32 + offset_y) as u32,
+    );
+    for (x, y, p) in img.enumerate_pixels() {
+        let alpha = u32::from(p.data[3]) * u32::from(color[3]) / 255;
+        mask.get_pixel_mut(sx + x, sy + y).data[3] = alpha as u8;
+    }
+    let mut canvas = if sigma > 0.0 {
+        imageops::blur(&mask, sigma)
+    } else {
+        mask
+    };
+    for p in canvas.pixels_mut() {
+        // The blur leaks the colour of the neighbours, the mask only has one
+        p.data[..3].copy_from_slice(&color[..3]);
+        p.data[3] = (f32::from(p.data[3]) * gain).min(255.0) as u8;
+    }
+    for (x, y, p) in img.enumerate_pixels() {
+        let under = canvas.get_pixel_mut(left + x, top + y);
+        *under = over(p, under);
+    }
+    canvas
+}
+
+/// Porter-Duff source over destination, straight alpha
+fn over(src: &Rgba<u8>, dst: &Rgba<u8>) -> Rgba<u8> {
+    let sa = f32::from(src.data[3]) / 255.0;
+    let da = f32::from(dst.data[3]) / 255.0;
+    let alpha = sa + da * (1.0 - sa);
+    if alpha <= 0.0 {
+        return Rgba { data: [0, 0, 0, 0] };
+    }
+    let channel = |c: usize| {
+        let v = (f32::from(src.data[c]) * sa + f32::from(dst.data[c]) * da * (1.0 - sa)) / alpha;
+        v.round().max(0.0).min(255.0) as u8
+    };
+    Rgba {
+        data: [
+            channel(0),
+            channel(1),
+            channel(2),
+            (alpha * 255.0).round() as u8,
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effects() {
+        assert_eq!(
+            gen_effect("shadow,x-2,cff0000").unwrap(),
+            Effect::Shadow {
+                offset_x: -2,
+                offset_y: 4,
+                blur: 8,
+                color: [255, 0, 0, 255],
+            }
+        );
+        let glow = gen_effect("glow,r6,cffcc0080").unwrap();
+        assert_eq!(gen_effect(&glow.to_string()).unwrap(), glow);
+        assert!(gen_effect("vignette,s200").is_err());
+        assert!(gen_effect("glow,cnope").is_err());
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(
+            20,
+            10,
+            image::Rgb {
+                data: [200, 200, 200],
+            },
+        ));
+        let vignetted = EffectHandler::new(gen_effect("vignette").unwrap())
+            .handle(img.clone())
+            .unwrap()
+            .to_rgb();
+        assert!(vignetted.get_pixel(0, 0).data[0] < vignetted.get_pixel(10, 5).data[0]);
+        // 12 pixels of blur around the shadow, moved by the offset
+        let shadowed = EffectHandler::new(gen_effect("shadow,x4,y4,b8").unwrap())
+            .handle(img)
+            .unwrap()
+            .to_rgba();
+        assert_eq!(shadowed.dimensions(), (44, 34));
+        assert_eq!(shadowed.get_pixel(0, 0).data[3], 0);
+        assert_eq!(shadowed.get_pixel(15, 15).data, [200, 200, 200, 255]);
+        assert!(shadowed.get_pixel(34, 24).data[3] > 0);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let mut img_info = ImageInfo::new("ferris", "png", Some(300), None);
+        img_info.effect(gen_effect("glow,r6,cffcc00").unwrap());
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+    }
+}
@@ -0,0 +1,358 @@
+use super::super::*;
+use super::crop::CropHandler;
+use image::{ImageBuffer, Luma};
+
+/// Largest output width and height, as announced by `info.json`
+pub const MAX_SIZE: u32 = 1280;
+
+/// The path parameters of an IIIF Image API 3.0 request
+#[derive(Debug, Clone, Hash)]
+pub struct IiifParams {
+    region: String,
+    size: String,
+    rotation: String,
+    quality: String,
+}
+
+impl IiifParams {
+    pub fn new(region: &str, size: &str, rotation: &str, quality: &str) -> Result<IiifParams> {
+        let params = IiifParams {
+            region: region.to_string(),
+            size: size.to_string(),
+            rotation: rotation.to_string(),
+            quality: quality.to_string(),
+        };
+        // Reject malformed requests before any decoding
+        params.parse()?;
+        Ok(params)
+    }
+
+    fn parse(&self) -> Result<(Region, Size, RotateHandler, QualityHandler)> {
+        Ok((
+            Region::parse(&self.region)?,
+            Size::parse(&self.size)?,
+            RotateHandler::parse(&self.rotation)?,
+            QualityHandler::parse(&self.quality)?,
+        ))
+    }
+
+    /// The handlers of the request on an original of `dimensions`: the region and the size are
+    /// the crop and the resize of the other requests
+    fn handlers(
+        &self,
+        opts: &ImageOption,
+        img_info: &ImageInfo,
+        dimensions: (u32, u32),
+    ) -> Result<Vec<Box<ImageHandler>>> {
+        let (region, size, rotation, quality) = self.parse()?;
+        let mut handlers: Vec<Box<ImageHandler>> = Vec::new();
+        let (x, y, width, height) = region.rect(dimensions)?;
+        if (width, height) != dimensions {
+            handlers.push(Box::new(CropHandler::new(x, y, width, height)));
+        }
+        let (nw, nh) = size.dimensions(width, height)?;
+        if (nw, nh) != (width, height) {
+            handlers.push(Box::new(Resizer {
+                width: Some(nw),
+                height: Some(nh),
+                filter_type: opts.filter_type(),
+                linear: img_info.linear_light.unwrap_or(opts.linear_light()),
+            }));
+        }
+        // Left out when they change nothing, 16-bit originals keep their depth then
+        if rotation.mirror || rotation.degrees != 0 {
+            handlers.push(Box::new(rotation));
+        }
+        match quality.quality {
+            Quality::Color => (),
+            _ => handlers.push(Box::new(quality)),
+        }
+        Ok(handlers)
+    }
+}
+
+#[derive(Debug)]
+enum Region {
+    Full,
+    Square,
+    Pixels(u32, u32, u32, u32),
+    Percent(f64, f64, f64, f64),
+}
+
+impl Region {
+    fn parse(region_s: &str) -> Result<Self> {
+        Ok(match region_s {
+            "full" => Region::Full,
+            "square" => Region::Square,
+            _ => {
+                if let Some(pct_s) = strip_prefix(region_s, "pct:") {
+                    let v = parse_list::<f64>(pct_s, 4)?;
+                    Region::Percent(v[0], v[1], v[2], v[3])
+                } else {
+                    let v = parse_list::<u32>(region_s, 4)?;
+                    Region::Pixels(v[0], v[1], v[2], v[3])
+                }
+            }
+        })
+    }
+
+    /// The `(x, y, width, height)` of the region in an image of `(width, height)`
+    fn rect(&self, (width, height): (u32, u32)) -> Result<(u32, u32, u32, u32)> {
+        let (x, y, w, h) = match *self {
+            Region::Full => (0, 0, width, height),
+            Region::Square => {
+                let side = width.min(height);
+                ((width - side) / 2, (height - side) / 2, side, side)
+            }
+            Region::Pixels(x, y, w, h) => (x, y, w, h),
+            Region::Percent(x, y, w, h) => (
+                (f64::from(width) * x / 100.0).round() as u32,
+                (f64::from(height) * y / 100.0).round() as u32,
+                (f64::from(width) * w / 100.0).round() as u32,
+                (f64::from(height) * h / 100.0).round() as u32,
+            ),
+        };
+        if x >= width || y >= height || w == 0 || h == 0 {
+            return Err(invalid_param("Region is outside of the image"));
+        }
+        // Regions extending beyond the image are cropped to it
+        Ok((x, y, w.min(width - x), h.min(height - y)))
+    }
+}
+
+#[derive(Debug)]
+enum SizeKind {
+    Max,
+    Width(u32),
+    Height(u32),
+    Percent(f64),
+    Exact(u32, u32),
+    Confined(u32, u32),
+}
+
+struct Size {
+    kind: SizeKind,
+    upscale: bool,
+}
+
+impl Size {
+    fn parse(size_s: &str) -> Result<Self> {
+        let upscale = size_s.starts_with('^');
+        let size_s = size_s.trim_start_matches('^');
+        let kind = if size_s == "max" {
+            SizeKind::Max
+        } else if let Some(pct_s) = strip_prefix(size_s, "pct:") {
+            SizeKind::Percent(parse_list::<f64>(pct_s, 1)?[0])
+        } else if let Some(wh_s) = strip_prefix(size_s, "!") {
+            let v = parse_list::<u32>(wh_s, 2)?;
+            SizeKind::Confined(v[0], v[1])
+        } else if size_s.ends_with(',') {
+            SizeKind::Width(parse_list::<u32>(size_s.trim_end_matches(','), 1)?[0])
+        } else if size_s.starts_with(',') {
+            SizeKind::Height(parse_list::<u32>(size_s.trim_start_matches(','), 1)?[0])
+        } else {
+            let v = parse_list::<u32>(size_s, 2)?;
+            SizeKind::Exact(v[0], v[1])
+        };
+        Ok(Size { kind, upscale })
+    }
+
+    fn dimensions(&self, width: u32, height: u32) -> Result<(u32, u32)> {
+        let (w, h) = (f64::from(width), f64::from(height));
+        let scaled = |ratio: f64| {
+            (
+                (w * ratio).round().max(1.0) as u32,
+                (h * ratio).round().max(1.0) as u32,
+            )
+        };
+        let max = f64::from(MAX_SIZE);
+        let (nw, nh) = match self.kind {
+            SizeKind::Max if self.upscale => scaled(f64::min(max / w, max / h)),
+            SizeKind::Max => scaled(f64::min(f64::min(max / w, max / h), 1.0)),
+            SizeKind::Width(nw) => (nw, scaled(f64::from(nw) / w).1),
+            SizeKind::Height(nh) => (scaled(f64::from(nh) / h).0, nh),
+            SizeKind::Percent(pct) => scaled(pct / 100.0),
+            SizeKind::Exact(nw, nh) => (nw, nh),
+            SizeKind::Confined(nw, nh) => {
+                let ratio = f64::min(f64::from(nw) / w, f64::from(nh) / h);
+                scaled(if self.upscale { ratio } else { ratio.min(1.0) })
+            }
+        };
+        if nw == 0 || nh == 0 {
+            Err(invalid_param("Size must not be zero"))
+        } else if !self.upscale && (nw > width || nh > height) {
+            Err(invalid_param("Size exceeds the region, use ^ to upscale"))
+        } else if nw > MAX_SIZE || nh > MAX_SIZE {
+            Err(invalid_param("Size exceeds maxWidth or maxHeight"))
+        } else {
+            Ok((nw, nh))
+        }
+    }
+}
+
+pub(super) struct RotateHandler {
+    mirror: bool,
+    degrees: u32,
+}
+
+impl RotateHandler {
+    pub(super) fn parse(rotation_s: &str) -> Result<Self> {
+        let mirror = rotation_s.starts_with('!');
+        let degrees: f64 = rotation_s
+            .trim_start_matches('!')
+            .parse()
+            .map_err(|_| invalid_param("Illegal rotation parameter"))?;
+        if degrees < 0.0 || degrees > 360.0 {
+            return Err(invalid_param("Rotation must be between 0 and 360"));
+        }
+        if degrees % 90.0 != 0.0 {
+            return Err(invalid_param(
+                "Only rotations by multiples of 90 are supported",
+            ));
+        }
+        Ok(RotateHandler {
+            mirror,
+            degrees: degrees as u32 % 360,
+        })
+    }
+}
+
+impl ImageHandler for RotateHandler {
+    fn name(&self) -> &str {
+        "rotate"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{},{}", self.mirror, self.degrees)
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        // Mirroring happens before the clockwise rotation
+        let img = if self.mirror { img.fliph() } else { img };
+        Ok(match self.degrees {
+            90 => img.rotate90(),
+            180 => img.rotate180(),
+            270 => img.rotate270(),
+            _ => img,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Quality {
+    Color,
+    Gray,
+    Bitonal,
+}
+
+pub(super) struct QualityHandler {
+    quality: Quality,
+}
+
+impl QualityHandler {
+    pub(super) fn parse(quality_s: &str) -> Result<Self> {
+        let quality = match quality_s {
+            "default" | "color" => Quality::Color,
+            "gray" => Quality::Gray,
+            "bitonal" => Quality::Bitonal,
+            _ => return Err(invalid_param("Unknown quality")),
+        };
+        Ok(QualityHandler { quality })
+    }
+}
+
+impl ImageHandler for QualityHandler {
+    fn name(&self) -> &str {
+        "quality"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{:?}", self.quality)
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        Ok(match self.quality {
+            Quality::Color => img,
+            Quality::Gray => img.grayscale(),
+            Quality::Bitonal => {
+                let gray = img.to_luma();
+                let bitonal = ImageBuffer::from_fn(gray.width(), gray.height(), |x, y| {
+                    let v = if gray.get_pixel(x, y).data[0] < 128 {
+                        0
+                    } else {
+                        255
+                    };
+                    Luma { data: [v] }
+                });
+                DynamicImage::ImageLuma8(bitonal)
+            }
+        })
+    }
+}
+
+fn strip_prefix<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    if s.starts_with(prefix) {
+        Some(&s[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+fn parse_list<T: std::str::FromStr>(list_s: &str, count: usize) -> Result<Vec<T>> {
+    let values = list_s
+        .split(',')
+        .map(|v| v.parse::<T>())
+        .collect::<std::result::Result<Vec<T>, _>>()
+        .map_err(|_| invalid_param(format!("Illegal parameter: {}", list_s)))?;
+    if values.len() != count {
+        return Err(invalid_param(format!("Illegal parameter: {}", list_s)));
+    }
+    Ok(values)
+}
+
+pub fn iiif(opts: &ImageOption, img_info: &ImageInfo) -> Result<u64> {
+    let params = img_info
+        .iiif
+        .as_ref()
+        .ok_or(err_msg("Missing IIIF parameters"))?;
+    // A format that can not be encoded is a bad request in IIIF
+    output_format(img_info.output_format()).map_err(|e| invalid_param(e.to_string()))?;
+    let dimensions = dimensions(opts, &img_info.fname())?;
+    let boxed = params.handlers(opts, img_info, dimensions)?;
+    pipeline(opts, img_info, boxed.iter().map(|h| h.as_ref()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_iiif() {
+        assert!(IiifParams::new("full", "max", "0", "default").is_ok());
+        assert!(IiifParams::new("pct:10,10,50,50", "^!300,300", "!90", "gray").is_ok());
+        assert!(IiifParams::new("0,0,10", "max", "0", "default").is_err());
+        assert!(IiifParams::new("full", "max", "45", "default").is_err());
+        let size = Size::parse("!300,300").unwrap();
+        assert_eq!(size.dimensions(1200, 800).unwrap(), (300, 200));
+        let size = Size::parse("2000,").unwrap();
+        assert!(size.dimensions(1200, 800).is_err());
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        // The crop and the resize of the other requests
+        let img_info = ImageInfo::new("ferris", "png", None, None);
+        let names = |params: IiifParams| -> Vec<String> {
+            let handlers = params.handlers(&opts, &img_info, (1200, 800)).unwrap();
+            handlers.iter().map(|h| h.name().to_string()).collect()
+        };
+        let params = IiifParams::new("0,0,600,400", "300,", "0", "default").unwrap();
+        assert_eq!(names(params), vec!["crop", "resize"]);
+        let params = IiifParams::new("full", "max", "0", "default").unwrap();
+        assert!(names(params).is_empty());
+        let mut img_info = ImageInfo::new("ferris", "png", None, None);
+        img_info.iiif(IiifParams::new("square", "200,", "90", "bitonal").unwrap());
+        img_info.output("jpg");
+        println!("hash: {}", iiif(&opts, &img_info).unwrap());
+        img_info.output("webp");
+        let e = iiif(&opts, &img_info).unwrap_err();
+        assert!(e.downcast_ref::<InvalidParameter>().is_some());
+    }
+}
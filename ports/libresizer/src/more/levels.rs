@@ -0,0 +1,294 @@
+use super::super::*;
+use image::{ImageBuffer, Rgba, RgbaImage};
+use std::fmt;
+
+/// Share of the darkest and brightest pixels ignored when stretching, against stray specks
+const CLIP_PERCENT: f64 = 0.5;
+const DEFAULT_TILES: u32 = 8;
+pub const MAX_TILES: u32 = 16;
+/// Histogram bins of a CLAHE tile are limited to this multiple of the average bin
+const CLIP_LIMIT: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq)]
+pub enum Levels {
+    /// Stretch each channel to the full range, also correcting colour casts
+    Auto,
+    /// Stretch the channels together, keeping the colours
+    Contrast,
+    /// Spread the luma histogram evenly
+    Equalize,
+    /// Contrast limited adaptive equalization over a grid of `n x n` tiles
+    Clahe(u32),
+}
+
+/// Parse `auto`, `contrast`, `equalize` or `clahe{n}`, the grid defaults to 8 tiles
+pub fn gen_levels(levels_s: &str) -> Result<Levels> {
+    match levels_s.to_lowercase().as_str() {
+        "auto" => Ok(Levels::Auto),
+        "contrast" => Ok(Levels::Contrast),
+        "equalize" => Ok(Levels::Equalize),
+        clahe if clahe.starts_with("clahe") => {
+            let tiles = if clahe.len() == 5 {
+                DEFAULT_TILES
+            } else {
+                clahe[5..]
+                    .parse()
+                    .map_err(|_| invalid_param("Illegal CLAHE tiles"))?
+            };
+            if tiles < 1 || tiles > MAX_TILES {
+                return Err(invalid_param(format!(
+                    "CLAHE tiles must be between 1 and {}",
+                    MAX_TILES
+                )));
+            }
+            Ok(Levels::Clahe(tiles))
+        }
+        _ => Err(invalid_param("Unknown levels mode")),
+    }
+}
+
+/// The mode in the syntax of `gen_levels`
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Levels::Auto => write!(f, "auto"),
+            Levels::Contrast => write!(f, "contrast"),
+            Levels::Equalize => write!(f, "equalize"),
+            Levels::Clahe(tiles) => write!(f, "clahe{}", tiles),
+        }
+    }
+}
+
+pub struct LevelsHandler {
+    levels: Levels,
+}
+
+impl LevelsHandler {
+    pub fn new(levels: Levels) -> Self {
+        LevelsHandler { levels }
+    }
+}
+
+impl ImageHandler for LevelsHandler {
+    fn name(&self) -> &str {
+        "levels"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("{:?}", self.levels)
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        let has_alpha = match img {
+            DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+            _ => false,
+        };
+        let mut rgba = img.to_rgba();
+        match self.levels {
+            Levels::Auto => {
+                let luts: Vec<[u8; 256]> = (0..3)
+                    .map(|c| stretch(&histogram(&rgba, |p| p.data[c])))
+                    .collect();
+                for p in rgba.pixels_mut() {
+                    for c in 0..3 {
+                        p.data[c] = luts[c][p.data[c] as usize];
+                    }
+                }
+            }
+            Levels::Contrast => {
+                let mut hist = [0u64; 256];
+                for p in rgba.pixels() {
+                    for c in 0..3 {
+                        hist[p.data[c] as usize] += 1;
+                    }
+                }
+                let lut = stretch(&hist);
+                for p in rgba.pixels_mut() {
+                    for c in 0..3 {
+                        p.data[c] = lut[p.data[c] as usize];
+                    }
+                }
+            }
+            Levels::Equalize => {
+                let lut = equalize(&histogram(&rgba, luma));
+                rgba = map_luma(&rgba, |_, _, y| lut[y as usize]);
+            }
+            Levels::Clahe(tiles) => rgba = clahe(&rgba, tiles),
+        }
+        let result = DynamicImage::ImageRgba8(rgba);
+        if has_alpha {
+            Ok(result)
+        } else {
+            Ok(DynamicImage::ImageRgb8(result.to_rgb()))
+        }
+    }
+}
+
+fn luma(p: &Rgba<u8>) -> u8 {
+    ((u32::from(p.data[0]) * 299 + u32::from(p.data[1]) * 587 + u32::from(p.data[2]) * 114) / 1000)
+        as u8
+}
+
+fn histogram<F: Fn(&Rgba<u8>) -> u8>(img: &RgbaImage, value: F) -> [u64; 256] {
+    let mut hist = [0u64; 256];
+    for p in img.pixels() {
+        hist[value(p) as usize] += 1;
+    }
+    hist
+}
+
+/// Linear mapping of the clipped histogram range to 0..255
+fn stretch(hist: &[u64; 256]) -> [u8; 256] {
+    let total: u64 = hist.iter().sum();
+    let clip = (total as f64 * CLIP_PERCENT / 100.0) as u64;
+    let bound = |values: Vec<usize>| {
+        let mut count = 0;
+        values
+            .into_iter()
+            .find(|v| {
+                count += hist[*v];
+                count > clip
+            })
+            .unwrap_or(0)
+    };
+    let low = bound((0..256).collect());
+    let high = bound((0..256).rev().collect());
+    let mut lut = [0u8; 256];
+    for (v, out) in lut.iter_mut().enumerate() {
+        *out = if high <= low {
+            v as u8
+        } else {
+            let scaled = (v as f64 - low as f64) * 255.0 / (high - low) as f64;
+            scaled.round().max(0.0).min(255.0) as u8
+        };
+    }
+    lut
+}
+
+/// The normalized cumulative histogram
+fn equalize(hist: &[u64; 256]) -> [u8; 256] {
+    let total: u64 = hist.iter().sum();
+    // The first occupied level maps to black
+    let first = hist.iter().find(|n| **n > 0).cloned().unwrap_or(0);
+    let mut lut = [0u8; 256];
+    let mut cdf = 0;
+    for (v, n) in hist.iter().enumerate() {
+        cdf += n;
+        lut[v] = if total <= first {
+            v as u8
+        } else {
+            ((cdf.saturating_sub(first)) as f64 * 255.0 / (total - first) as f64).round() as u8
+        };
+    }
+    lut
+}
+
+/// Shift the colour channels by the change of the luma, keeping the chroma
+fn map_luma<F: Fn(u32, u32, u8) -> u8>(img: &RgbaImage, map: F) -> RgbaImage {
+    ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+        let p = img.get_pixel(x, y);
+        let y0 = luma(p);
+        let delta = i32::from(map(x, y, y0)) - i32::from(y0);
+        let channel = |c: usize| (i32::from(p.data[c]) + delta).max(0).min(255) as u8;
+        Rgba {
+            data: [channel(0), channel(1), channel(2), p.data[3]],
+        }
+    })
+}
+
+fn clahe(img: &RgbaImage, tiles: u32) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let tiles_x = tiles.min(width).max(1);
+    let tiles_y = tiles.min(height).max(1);
+    let tile_w = f64::from(width) / f64::from(tiles_x);
+    let tile_h = f64::from(height) / f64::from(tiles_y);
+    let mut luts = Vec::with_capacity((tiles_x * tiles_y) as usize);
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let (x0, x1) = (
+                (f64::from(tx) * tile_w) as u32,
+                (f64::from(tx + 1) * tile_w) as u32,
+            );
+            let (y0, y1) = (
+                (f64::from(ty) * tile_h) as u32,
+                (f64::from(ty + 1) * tile_h) as u32,
+            );
+            let mut hist = [0u64; 256];
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    hist[luma(img.get_pixel(x, y)) as usize] += 1;
+                }
+            }
+            // Clip the peaks and spread the excess over all bins
+            let total: u64 = hist.iter().sum();
+            let limit = ((total as f64 / 256.0 * CLIP_LIMIT).ceil() as u64).max(1);
+            let mut excess = 0;
+            for n in hist.iter_mut() {
+                if *n > limit {
+                    excess += *n - limit;
+                    *n = limit;
+                }
+            }
+            let residual = excess % 256;
+            for (v, n) in hist.iter_mut().enumerate() {
+                *n += excess / 256;
+                // The rest at regular steps, away from either end
+                if residual > 0 && v as u64 % (256 / residual) == 0 {
+                    *n += 1;
+                }
+            }
+            luts.push(equalize(&hist));
+        }
+    }
+    // Bilinear interpolation between the mappings of the four closest tile centers
+    let neighbours = |pos: u32, size: f64, count: u32| {
+        let t = ((f64::from(pos) + 0.5) / size - 0.5).max(0.0);
+        let i = (t as u32).min(count - 1);
+        (i, (i + 1).min(count - 1), t - f64::from(i))
+    };
+    map_luma(img, |x, y, v| {
+        let (x0, x1, fx) = neighbours(x, tile_w, tiles_x);
+        let (y0, y1, fy) = neighbours(y, tile_h, tiles_y);
+        let at = |tx: u32, ty: u32| f64::from(luts[(ty * tiles_x + tx) as usize][v as usize]);
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        (top * (1.0 - fy) + bottom * fy).round().max(0.0).min(255.0) as u8
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(gen_levels("clahe").unwrap(), Levels::Clahe(8));
+        assert_eq!(Levels::Clahe(4).to_string(), "clahe4");
+        assert!(gen_levels("clahe99").is_err());
+        // A grey, low contrast scan
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, _| {
+            let v = 100 + x as u8;
+            image::Rgb { data: [v, v, v] }
+        }));
+        // CLAHE limits the amplification, the global modes reach the full range
+        for (mode, range) in &[
+            ("auto", 200),
+            ("contrast", 200),
+            ("equalize", 200),
+            ("clahe2", 63),
+        ] {
+            let result = LevelsHandler::new(gen_levels(mode).unwrap())
+                .handle(img.clone())
+                .unwrap()
+                .to_rgb();
+            let (min, max) = result.pixels().fold((255, 0), |(min, max), p| {
+                (p.data[0].min(min), p.data[0].max(max))
+            });
+            assert!(max - min > *range, "{}: {}..{}", mode, min, max);
+        }
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let mut img_info = ImageInfo::new("ferris", "png", Some(300), None);
+        img_info.levels(Levels::Clahe(8));
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+    }
+}
@@ -0,0 +1,253 @@
+use super::super::*;
+use image::{ImageBuffer, Rgba, RgbaImage};
+
+/// Seams remove at most half of the scaled dimension, a plain resize does the rest
+const MAX_SEAM_RATIO: f64 = 0.5;
+/// Seams times the pixels they are searched in, larger carvings are refused
+const MAX_CARVED_PIXELS: u64 = 250_000_000;
+
+/// Content-aware resize, removing the seams of least energy instead of stretching
+pub struct LiquidResizer {
+    width: Option<u32>,
+    height: Option<u32>,
+    filter_type: FilterType,
+}
+
+impl LiquidResizer {
+    pub fn new(width: Option<u32>, height: Option<u32>, filter_type: FilterType) -> Self {
+        LiquidResizer {
+            width,
+            height,
+            filter_type,
+        }
+    }
+}
+
+impl ImageHandler for LiquidResizer {
+    fn name(&self) -> &str {
+        "liquid"
+    }
+
+    fn cache_key(&self) -> String {
+        format!(
+            "{:?}x{:?},{}",
+            self.width,
+            self.height,
+            filter_name(self.filter_type)
+        )
+    }
+
+    fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+        let (width, height) = match (self.width, self.height) {
+            (Some(width), Some(height)) => (width, height),
+            // The aspect ratio is kept, there is nothing to carve
+            _ => {
+                return Resizer {
+                    width: self.width,
+                    height: self.height,
+                    filter_type: self.filter_type,
+                    linear: false,
+                }
+                .handle(img)
+            }
+        };
+        let (w, h) = img.dimensions();
+        // Scale until one side fits, the other one is carved
+        let scale = f64::max(
+            f64::from(width) / f64::from(w),
+            f64::from(height) / f64::from(h),
+        );
+        let carve = |target: u32, length: u32| {
+            let scaled = (f64::from(length) * scale).round() as u32;
+            scaled
+                .max(target)
+                .min((f64::from(target) / (1.0 - MAX_SEAM_RATIO)).round() as u32)
+        };
+        let (sw, sh) = (carve(width, w), carve(height, h));
+        // Every seam is searched in the whole image
+        let carved_pixels = u64::from(sw - width) * u64::from(sw) * u64::from(sh)
+            + u64::from(sh - height) * u64::from(width) * u64::from(sh);
+        if carved_pixels > MAX_CARVED_PIXELS {
+            return Err(invalid_param(
+                "Too many seams to carve, use a smaller image or size",
+            ));
+        }
+        let has_alpha = match img {
+            DynamicImage::ImageLumaA8(_) | DynamicImage::ImageRgba8(_) => true,
+            _ => false,
+        };
+        let scaled = if (sw, sh) == (w, h) {
+            img
+        } else {
+            img.resize_exact(sw, sh, self.filter_type)
+        };
+        let mut carver = Carver::new(&scaled.to_rgba());
+        carver.carve(width);
+        if sh > height {
+            carver.transpose();
+            carver.carve(height);
+            carver.transpose();
+        }
+        let result = DynamicImage::ImageRgba8(carver.into_image());
+        if has_alpha {
+            Ok(result)
+        } else {
+            Ok(DynamicImage::ImageRgb8(result.to_rgb()))
+        }
+    }
+}
+
+struct Carver {
+    width: usize,
+    height: usize,
+    pixels: Vec<Rgba<u8>>,
+    luma: Vec<i32>,
+    energy: Vec<u32>,
+}
+
+impl Carver {
+    fn new(img: &RgbaImage) -> Self {
+        let pixels: Vec<Rgba<u8>> = img.pixels().cloned().collect();
+        let mut carver = Carver {
+            width: img.width() as usize,
+            height: img.height() as usize,
+            luma: pixels.iter().map(luma).collect(),
+            energy: vec![0; pixels.len()],
+            pixels,
+        };
+        for y in 0..carver.height {
+            for x in 0..carver.width {
+                carver.update_energy(x, y);
+            }
+        }
+        carver
+    }
+
+    /// Gradient magnitude of the luma
+    fn update_energy(&mut self, x: usize, y: usize) {
+        let at = |x: usize, y: usize| self.luma[y * self.width + x];
+        let dx = at((x + 1).min(self.width - 1), y) - at(x.saturating_sub(1), y);
+        let dy = at(x, (y + 1).min(self.height - 1)) - at(x, y.saturating_sub(1));
+        self.energy[y * self.width + x] = (dx.abs() + dy.abs()) as u32;
+    }
+
+    /// Remove vertical seams until the width is reached
+    fn carve(&mut self, width: u32) {
+        while self.width > width as usize && self.width > 1 {
+            let seam = self.find_seam();
+            self.remove_seam(&seam);
+        }
+    }
+
+    /// The vertical path of least energy, one x per row
+    fn find_seam(&self) -> Vec<usize> {
+        let w = self.width;
+        let mut cost: Vec<u64> = self.energy[..w].iter().map(|e| u64::from(*e)).collect();
+        let mut from = vec![0u8; w * self.height];
+        for y in 1..self.height {
+            let mut row = Vec::with_capacity(w);
+            for x in 0..w {
+                let (mut best, mut dir) = (cost[x], 1);
+                if x > 0 && cost[x - 1] < best {
+                    best = cost[x - 1];
+                    dir = 0;
+                }
+                if x + 1 < w && cost[x + 1] < best {
+                    best = cost[x + 1];
+                    dir = 2;
+                }
+                from[y * w + x] = dir;
+                row.push(best + u64::from(self.energy[y * w + x]));
+            }
+            cost = row;
+        }
+        let mut x = (0..w).min_by_key(|x| cost[*x]).unwrap_or(0);
+        let mut seam = vec![0; self.height];
+        for y in (0..self.height).rev() {
+            seam[y] = x;
+            if y > 0 {
+                x = x + from[y * w + x] as usize - 1;
+            }
+        }
+        seam
+    }
+
+    fn remove_seam(&mut self, seam: &[usize]) {
+        let w = self.width;
+        let mut pixels = Vec::with_capacity(self.pixels.len() - self.height);
+        let mut luma = Vec::with_capacity(pixels.capacity());
+        let mut energy = Vec::with_capacity(pixels.capacity());
+        for (y, sx) in seam.iter().enumerate() {
+            let row = y * w..(y + 1) * w;
+            for x in row.filter(|i| *i != y * w + sx) {
+                pixels.push(self.pixels[x]);
+                luma.push(self.luma[x]);
+                energy.push(self.energy[x]);
+            }
+        }
+        self.pixels = pixels;
+        self.luma = luma;
+        self.energy = energy;
+        self.width -= 1;
+        // Only the neighbours of the seam see different pixels around them
+        for (y, sx) in seam.iter().enumerate() {
+            for x in sx.saturating_sub(2)..(sx + 2).min(self.width) {
+                self.update_energy(x, y);
+            }
+        }
+    }
+
+    fn transpose(&mut self) {
+        let (w, h) = (self.width, self.height);
+        let index = |i: usize| (i % h) * w + i / h;
+        self.pixels = (0..w * h).map(|i| self.pixels[index(i)]).collect();
+        self.luma = (0..w * h).map(|i| self.luma[index(i)]).collect();
+        self.energy = (0..w * h).map(|i| self.energy[index(i)]).collect();
+        self.width = h;
+        self.height = w;
+        // The horizontal and vertical gradients swap, their sum stays the same
+    }
+
+    fn into_image(self) -> RgbaImage {
+        let (w, pixels) = (self.width, self.pixels);
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            pixels[y as usize * w + x as usize]
+        })
+    }
+}
+
+fn luma(p: &Rgba<u8>) -> i32 {
+    (i32::from(p.data[0]) * 299 + i32::from(p.data[1]) * 587 + i32::from(p.data[2]) * 114) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_liquid() {
+        // A bright square on the left of a flat background survives the carving
+        let img = DynamicImage::ImageRgb8(ImageBuffer::from_fn(40, 20, |x, y| {
+            if x >= 4 && x < 12 && y >= 6 && y < 14 {
+                image::Rgb {
+                    data: [255, 255, 255],
+                }
+            } else {
+                image::Rgb { data: [0, 0, 0] }
+            }
+        }));
+        let resizer = LiquidResizer::new(Some(30), Some(20), FilterType::Triangle);
+        let carved = resizer.handle(img).unwrap().to_rgb();
+        assert_eq!(carved.dimensions(), (30, 20));
+        let white = carved.pixels().filter(|p| p.data[0] == 255).count();
+        assert_eq!(white, 64);
+        let large = DynamicImage::new_rgb8(1200, 1200);
+        let resizer = LiquidResizer::new(Some(600), Some(1200), FilterType::Triangle);
+        let e = resizer.handle(large).err().unwrap();
+        assert!(e.downcast_ref::<InvalidParameter>().is_some());
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let mut img_info = ImageInfo::new("ferris", "png", Some(300), Some(300));
+        img_info.liquid(true);
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+    }
+}
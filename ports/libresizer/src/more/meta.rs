@@ -0,0 +1,136 @@
+use super::super::*;
+use crate::exif::{self, ExifSummary};
+use crate::metadata::png_chunks;
+use image::ColorType;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+/// What clients need to know about an original without downloading it
+#[derive(Debug, Clone)]
+pub struct ImageMeta {
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    pub color_type: String,
+    /// Bits per channel
+    pub bit_depth: u8,
+    pub file_size: u64,
+    /// Frames of animated GIF and PNG images, 1 for still images
+    pub frames: u32,
+    pub exif: Option<ExifSummary>,
+    /// SHA-256 of the original file, hex encoded
+    pub hash: String,
+}
+
+pub fn meta(opts: &ImageOption, fname: &str) -> Result<ImageMeta> {
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(fname);
+    let data = fs::read(&fpath)?;
+    let format = image::guess_format(&data)?;
+    let (width, height, color_type, bit_depth) = if let Some(deep) = DeepImage::decode(&data)? {
+        let (width, height) = deep.buffer.dimensions();
+        let color_type = match deep.channels {
+            1 => "gray",
+            2 => "gray_alpha",
+            3 => "rgb",
+            _ => "rgba",
+        };
+        (width, height, color_type, 16)
+    } else {
+        let img = image::load_from_memory_with_format(&data, format)?;
+        let (width, height) = img.dimensions();
+        let color_type = match img.color() {
+            ColorType::Gray(_) => "gray",
+            ColorType::GrayA(_) => "gray_alpha",
+            ColorType::RGBA(_) => "rgba",
+            ColorType::Palette(_) => "palette",
+            _ => "rgb",
+        };
+        (width, height, color_type, 8)
+    };
+    let exif = Metadata::read(&data)?
+        .exif
+        .and_then(|exif| exif::summarize(&exif));
+    Ok(ImageMeta {
+        width,
+        height,
+        format: format!("{:?}", format).to_lowercase(),
+        color_type: color_type.to_string(),
+        bit_depth,
+        file_size: data.len() as u64,
+        frames: count_frames(&data),
+        exif,
+        hash: format!("{:x}", Sha256::digest(&data)),
+    })
+}
+
+fn count_frames(data: &[u8]) -> u32 {
+    if data.starts_with(b"GIF8") {
+        gif_frames(data).unwrap_or(1)
+    } else if data.starts_with(b"\x89PNG") {
+        // The frame count of APNG images, in the acTL chunk
+        png_chunks(data)
+            .take_while(|(kind, _, _)| kind != b"IDAT")
+            .find(|(kind, _, _)| kind == b"acTL")
+            .and_then(|(_, payload, _)| payload.get(..4).map(|b| b.to_vec()))
+            .map(|b| {
+                u32::from(b[0]) << 24
+                    | u32::from(b[1]) << 16
+                    | u32::from(b[2]) << 8
+                    | u32::from(b[3])
+            })
+            .unwrap_or(1)
+    } else {
+        1
+    }
+}
+
+/// Count the image descriptors, skipping the data blocks
+fn gif_frames(data: &[u8]) -> Option<u32> {
+    let color_table = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 7) + 1)
+        } else {
+            0
+        }
+    };
+    let skip_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *data.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+    let mut pos = 13 + color_table(*data.get(10)?);
+    let mut frames = 0;
+    loop {
+        match *data.get(pos)? {
+            0x21 => pos = skip_blocks(pos + 2)?,
+            0x2C => {
+                frames += 1;
+                pos += 10 + color_table(*data.get(pos + 9)?);
+                // LZW minimum code size, then the image data
+                pos = skip_blocks(pos + 1)?;
+            }
+            0x3B => return Some(frames),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta() {
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let meta = meta(&opts, "ferris.png").unwrap();
+        assert_eq!(meta.format, "png");
+        assert_eq!(meta.frames, 1);
+        assert_eq!(meta.hash.len(), 64);
+        println!("meta: {:?}", meta);
+    }
+}
@@ -1,5 +1,29 @@
 pub mod blur;
+pub mod chain;
+pub mod compose;
+pub mod convolve;
 pub mod crop;
+pub mod denoise;
+pub mod effects;
+pub mod iiif;
+pub mod levels;
+pub mod liquid;
+pub mod meta;
+pub mod palette;
+pub mod phash;
+pub mod placeholder;
+pub mod sprite;
+pub mod tile;
 
+#[allow(deprecated)]
 pub use self::blur::blur;
+pub use self::compose::compose;
+#[allow(deprecated)]
 pub use self::crop::crop;
+pub use self::iiif::iiif;
+pub use self::meta::meta;
+pub use self::palette::palette;
+pub use self::phash::HashIndex;
+pub use self::placeholder::placeholder;
+pub use self::sprite::sprite;
+pub use self::tile::tile;
@@ -0,0 +1,141 @@
+use super::super::*;
+use std::fs;
+
+/// Colours are counted on a small copy of the original
+const SAMPLE_SIZE: u32 = 100;
+pub const MAX_COLORS: usize = 16;
+
+/// A colour of the palette with its share of the (opaque) pixels
+#[derive(Debug, Clone, PartialEq)]
+pub struct Swatch {
+    pub rgb: [u8; 3],
+    /// Percentage, between 0 and 100
+    pub population: f32,
+}
+
+impl Swatch {
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.rgb[0], self.rgb[1], self.rgb[2])
+    }
+}
+
+/// Up to `colors` swatches of an original, most common first. The first one is the dominant colour.
+pub fn palette(opts: &ImageOption, fname: &str, colors: usize) -> Result<Vec<Swatch>> {
+    if colors < 1 || colors > MAX_COLORS {
+        return Err(invalid_param(format!(
+            "The number of colours must be between 1 and {}",
+            MAX_COLORS
+        )));
+    }
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(fname);
+    let img = image::load_from_memory(&fs::read(&fpath)?)?;
+    Ok(extract(&img, colors))
+}
+
+/// Median cut: split the box with the most spread pixels along its widest channel
+fn extract(img: &DynamicImage, colors: usize) -> Vec<Swatch> {
+    let (width, height) = img.dimensions();
+    let small = if width > SAMPLE_SIZE || height > SAMPLE_SIZE {
+        img.resize(SAMPLE_SIZE, SAMPLE_SIZE, FilterType::Triangle)
+    } else {
+        img.clone()
+    };
+    // Mostly transparent pixels do not show, they would only bring the background colour in
+    let pixels: Vec<[u8; 3]> = small
+        .to_rgba()
+        .pixels()
+        .filter(|p| p.data[3] >= 128)
+        .map(|p| [p.data[0], p.data[1], p.data[2]])
+        .collect();
+    if pixels.is_empty() {
+        return Vec::new();
+    }
+    let total = pixels.len() as f32;
+    let mut boxes = vec![pixels];
+    while boxes.len() < colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| {
+                let (channel, range) = widest_channel(b);
+                (i, channel, u64::from(range) * b.len() as u64)
+            })
+            .max_by_key(|(_, _, score)| *score);
+        let (index, channel) = match widest {
+            Some((i, channel, score)) if score > 0 => (i, channel),
+            // Fewer distinct colours than requested
+            _ => break,
+        };
+        let mut cut = boxes.swap_remove(index);
+        cut.sort_by_key(|p| p[channel]);
+        // Equal values stay on the same side of the cut
+        let median = cut[cut.len() / 2][channel];
+        let mut at = cut.iter().position(|p| p[channel] >= median).unwrap_or(0);
+        if at == 0 {
+            at = cut
+                .iter()
+                .position(|p| p[channel] > median)
+                .unwrap_or(cut.len());
+        }
+        let upper = cut.split_off(at);
+        boxes.push(cut);
+        boxes.push(upper);
+    }
+    let mut swatches: Vec<Swatch> = boxes
+        .iter()
+        .map(|b| {
+            let mut sum = [0u64; 3];
+            for p in b {
+                for c in 0..3 {
+                    sum[c] += u64::from(p[c]);
+                }
+            }
+            let n = b.len() as u64;
+            Swatch {
+                rgb: [
+                    ((sum[0] + n / 2) / n) as u8,
+                    ((sum[1] + n / 2) / n) as u8,
+                    ((sum[2] + n / 2) / n) as u8,
+                ],
+                population: b.len() as f32 * 100.0 / total,
+            }
+        })
+        .collect();
+    swatches.sort_by(|a, b| b.population.partial_cmp(&a.population).unwrap());
+    swatches
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|c| {
+            let min = pixels.iter().map(|p| p[c]).min().unwrap_or(0);
+            let max = pixels.iter().map(|p| p[c]).max().unwrap_or(0);
+            (c, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap_or((0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette() {
+        let img = DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(10, 10, |x, _| {
+            if x < 7 {
+                image::Rgb { data: [200, 0, 0] }
+            } else {
+                image::Rgb { data: [0, 0, 200] }
+            }
+        }));
+        let swatches = extract(&img, 4);
+        assert_eq!(swatches.len(), 2);
+        assert_eq!(swatches[0].hex(), "#c80000");
+        assert_eq!(swatches[0].population, 70.0);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        println!("palette: {:?}", palette(&opts, "ferris.png", 5).unwrap());
+    }
+}
@@ -0,0 +1,377 @@
+use super::super::*;
+use image::GrayImage;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+const INDEX_FILE: &str = "phash.idx";
+/// In place of the hashes of the originals that can not be decoded
+const UNDECODABLE: &str = "-";
+const EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "ico", "tif", "tiff"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashKind {
+    /// Pixels brighter than the mean
+    Average,
+    /// Pixels brighter than their right neighbour
+    Difference,
+    /// Low frequencies of the DCT above their median
+    Perceptual,
+}
+
+/// The 64-bit perceptual hashes of an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PerceptualHash {
+    pub ahash: u64,
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+impl PerceptualHash {
+    pub fn new(img: &DynamicImage) -> Self {
+        PerceptualHash {
+            ahash: ahash(&gray(img, 8, 8)),
+            dhash: dhash(&gray(img, 9, 8)),
+            phash: phash(&gray(img, 32, 32)),
+        }
+    }
+
+    pub fn get(&self, kind: HashKind) -> u64 {
+        match kind {
+            HashKind::Average => self.ahash,
+            HashKind::Difference => self.dhash,
+            HashKind::Perceptual => self.phash,
+        }
+    }
+
+    /// Hamming distance, the number of differing bits
+    pub fn distance(&self, other: &PerceptualHash, kind: HashKind) -> u32 {
+        (self.get(kind) ^ other.get(kind)).count_ones()
+    }
+}
+
+pub fn gen_hash_kind(kind_s: &str) -> Result<HashKind> {
+    match kind_s.to_lowercase().as_str() {
+        "ahash" | "average" => Ok(HashKind::Average),
+        "dhash" | "difference" => Ok(HashKind::Difference),
+        "phash" | "perceptual" => Ok(HashKind::Perceptual),
+        _ => Err(invalid_param("Unknown hash kind")),
+    }
+}
+
+fn gray(img: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    img.resize_exact(width, height, FilterType::Triangle)
+        .to_luma()
+}
+
+fn ahash(img: &GrayImage) -> u64 {
+    let mean = img.pixels().map(|p| u32::from(p.data[0])).sum::<u32>() / 64;
+    img.pixels().fold(0, |hash, p| {
+        hash << 1 | (u32::from(p.data[0]) > mean) as u64
+    })
+}
+
+fn dhash(img: &GrayImage) -> u64 {
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = img.get_pixel(x, y).data[0];
+            let right = img.get_pixel(x + 1, y).data[0];
+            hash = hash << 1 | (left > right) as u64;
+        }
+    }
+    hash
+}
+
+fn phash(img: &GrayImage) -> u64 {
+    let n = f64::from(img.width());
+    let mut coefficients = Vec::with_capacity(64);
+    for v in 0..8 {
+        for u in 0..8 {
+            let mut sum = 0.0;
+            for (x, y, p) in img.enumerate_pixels() {
+                sum += f64::from(p.data[0])
+                    * (PI * f64::from(u) * (2.0 * f64::from(x) + 1.0) / (2.0 * n)).cos()
+                    * (PI * f64::from(v) * (2.0 * f64::from(y) + 1.0) / (2.0 * n)).cos();
+            }
+            coefficients.push(sum);
+        }
+    }
+    // The DC term only carries the average brightness, it stays out of the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let median = sorted[sorted.len() / 2];
+    coefficients
+        .iter()
+        .fold(0, |hash, c| hash << 1 | (*c > median) as u64)
+}
+
+/// Size and modification time of an original, its hashes are computed again when they change
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stamp {
+    len: u64,
+    modified: Duration,
+}
+
+impl Stamp {
+    fn read(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Stamp {
+            len: metadata.len(),
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?,
+        })
+    }
+}
+
+/// The perceptual hashes of the originals, persisted in the output directory. A
+/// `name\tsize\tmtime\tahash\tdhash\tphash` line is written per original, e.g.
+/// `ferris.png\t12345\t1546300800.000000000\t…`, with `-` in place of the hashes of the originals
+/// that can not be decoded.
+///
+/// The originals are hashed by `refresh`, or by `hash` for the ones it has not seen yet. The
+/// lock is only held to read and update the entries, never while decoding.
+pub struct HashIndex {
+    path: PathBuf,
+    originals: Mutex<HashMap<String, (Stamp, Option<PerceptualHash>)>>,
+}
+
+impl HashIndex {
+    /// Read the index of the output directory, the originals are not looked at
+    pub fn load(output_dir: &str) -> Result<Self> {
+        let mut path = PathBuf::from(output_dir);
+        path.push(INDEX_FILE);
+        let mut originals = HashMap::new();
+        if let Ok(index) = fs::read_to_string(&path) {
+            for line in index.lines() {
+                let fields: Vec<&str> = line.split('\t').collect();
+                if fields.len() != 6 {
+                    continue;
+                }
+                let stamp = match (fields[1].parse(), parse_modified(fields[2])) {
+                    (Ok(len), Some(modified)) => Stamp { len, modified },
+                    _ => continue,
+                };
+                let parse = |s: &str| u64::from_str_radix(s, 16);
+                if fields[3..].iter().all(|f| *f == UNDECODABLE) {
+                    originals.insert(fields[0].to_string(), (stamp, None));
+                } else if let (Ok(ahash), Ok(dhash), Ok(phash)) =
+                    (parse(fields[3]), parse(fields[4]), parse(fields[5]))
+                {
+                    let hash = PerceptualHash {
+                        ahash,
+                        dhash,
+                        phash,
+                    };
+                    originals.insert(fields[0].to_string(), (stamp, Some(hash)));
+                }
+            }
+        }
+        Ok(HashIndex {
+            path,
+            originals: Mutex::new(originals),
+        })
+    }
+
+    /// Hash the new and modified originals and forget the deleted ones, returns the number of
+    /// originals hashed
+    pub fn refresh(&self, opts: &ImageOption) -> Result<usize> {
+        let mut seen = Vec::new();
+        let mut hashed = 0;
+        for entry in fs::read_dir(opts.input_dir())? {
+            let path = entry?.path();
+            let is_image = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| EXTENSIONS.contains(&e.to_lowercase().as_str()))
+                == Some(true);
+            let fname = match path.file_name().and_then(|f| f.to_str()) {
+                Some(fname) if is_image && path.is_file() => fname.to_string(),
+                _ => continue,
+            };
+            // Deleted in the meantime
+            let stamp = match Stamp::read(&path) {
+                Ok(stamp) => stamp,
+                Err(_) => continue,
+            };
+            if !self.is_current(&fname, stamp) {
+                self.insert(&fname, stamp, hash_original(&path)?);
+                hashed += 1;
+            }
+            seen.push(fname);
+        }
+        let removed = {
+            let mut originals = self.originals.lock().unwrap();
+            let len = originals.len();
+            originals.retain(|fname, _| seen.contains(fname));
+            originals.len() != len
+        };
+        if hashed > 0 || removed {
+            self.rewrite()?;
+        }
+        Ok(hashed)
+    }
+
+    /// The hashes of an original, computed now if it changed since the last `refresh`
+    pub fn hash(&self, opts: &ImageOption, fname: &str) -> Result<PerceptualHash> {
+        let mut path = PathBuf::from(&opts.input_dir());
+        path.push(fname);
+        let stamp = Stamp::read(&path)?;
+        let hash = match self.get(fname, stamp) {
+            Some(hash) => hash,
+            None => {
+                let hash = hash_original(&path)?;
+                self.insert(fname, stamp, hash);
+                self.rewrite()?;
+                hash
+            }
+        };
+        hash.ok_or_else(|| {
+            Error::from(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Undecodable original: {}", fname),
+            ))
+        })
+    }
+
+    /// Other originals within `threshold` bits of the given one, closest first
+    pub fn duplicates(
+        &self,
+        opts: &ImageOption,
+        fname: &str,
+        kind: HashKind,
+        threshold: u32,
+    ) -> Result<(PerceptualHash, Vec<(String, u32)>)> {
+        let hash = self.hash(opts, fname)?;
+        let mut duplicates: Vec<(String, u32)> = self
+            .originals
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name.as_str() != fname)
+            .filter_map(|(name, (_, other))| {
+                other.map(|other| (name.clone(), hash.distance(&other, kind)))
+            })
+            .filter(|(_, distance)| *distance <= threshold)
+            .collect();
+        duplicates.sort_by_key(|(name, distance)| (*distance, name.clone()));
+        Ok((hash, duplicates))
+    }
+
+    pub fn len(&self) -> usize {
+        self.originals.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.originals.lock().unwrap().is_empty()
+    }
+
+    fn is_current(&self, fname: &str, stamp: Stamp) -> bool {
+        self.originals.lock().unwrap().get(fname).map(|(s, _)| *s) == Some(stamp)
+    }
+
+    /// `None` unless the original is hashed and unchanged since, `Some(None)` if undecodable
+    fn get(&self, fname: &str, stamp: Stamp) -> Option<Option<PerceptualHash>> {
+        match self.originals.lock().unwrap().get(fname) {
+            Some((s, hash)) if *s == stamp => Some(*hash),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, fname: &str, stamp: Stamp, hash: Option<PerceptualHash>) {
+        self.originals
+            .lock()
+            .unwrap()
+            .insert(fname.to_string(), (stamp, hash));
+    }
+
+    fn rewrite(&self) -> Result<()> {
+        let lines: Vec<String> = self
+            .originals
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fname, (stamp, hash))| {
+                let hashes = match hash {
+                    Some(h) => format!("{:016x}\t{:016x}\t{:016x}", h.ahash, h.dhash, h.phash),
+                    None => format!("{}\t{}\t{}", UNDECODABLE, UNDECODABLE, UNDECODABLE),
+                };
+                format!(
+                    "{}\t{}\t{}.{:09}\t{}\n",
+                    fname,
+                    stamp.len,
+                    stamp.modified.as_secs(),
+                    stamp.modified.subsec_nanos(),
+                    hashes
+                )
+            })
+            .collect();
+        fs::write(&self.path, lines.concat())?;
+        Ok(())
+    }
+}
+
+/// `seconds.nanoseconds` since the epoch
+fn parse_modified(modified_s: &str) -> Option<Duration> {
+    let mut parts = modified_s.splitn(2, '.');
+    let secs = parts.next()?.parse().ok()?;
+    let nanos = parts.next()?.parse().ok()?;
+    Some(Duration::new(secs, nanos))
+}
+
+/// Not decodable, e.g. an unsupported variant of the format, is recorded as `None` so that the
+/// original is not read again until it is modified
+fn hash_original(path: &Path) -> Result<Option<PerceptualHash>> {
+    Ok(image::load_from_memory(&fs::read(path)?)
+        .ok()
+        .map(|img| PerceptualHash::new(&img)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phash() {
+        let img = DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(64, 48, |x, y| {
+            let v = ((x * 4 + y * 2) % 256) as u8;
+            image::Rgb { data: [v, v, v] }
+        }));
+        let hash = PerceptualHash::new(&img);
+        let smaller = PerceptualHash::new(&img.resize(32, 24, FilterType::Lanczos3));
+        assert!(hash.distance(&smaller, HashKind::Perceptual) <= 4);
+        assert!(hash.distance(&smaller, HashKind::Difference) <= 4);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let index = HashIndex::load(opts.output_dir()).unwrap();
+        println!(
+            "duplicates: {:?}",
+            index.duplicates(&opts, "ferris.png", HashKind::Perceptual, 10)
+        );
+        // Undecodable originals are recorded, not looked up
+        let dir = std::env::temp_dir().join("irir-phash-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("broken.png"), b"not a png").unwrap();
+        let dir_s = dir.to_str().unwrap();
+        let opts = ImageOption::new(dir_s, dir_s, FilterType::Lanczos3);
+        let index = HashIndex::load(dir_s).unwrap();
+        assert_eq!(index.refresh(&opts).unwrap(), 1);
+        assert_eq!(index.refresh(&opts).unwrap(), 0);
+        let recorded = fs::read_to_string(dir.join(INDEX_FILE)).unwrap();
+        assert!(recorded.starts_with("broken.png\t9\t") && recorded.ends_with("\t-\t-\t-\n"));
+        let err = index
+            .duplicates(&opts, "broken.png", HashKind::Perceptual, 10)
+            .unwrap_err();
+        assert!(err.find_root_cause().downcast_ref::<io::Error>().is_some());
+        // Seen by the request before the next refresh, even within the same second
+        img.save(dir.join("broken.png")).unwrap();
+        assert_eq!(index.hash(&opts, "broken.png").unwrap(), hash);
+        let reloaded = HashIndex::load(dir_s).unwrap();
+        assert_eq!(reloaded.refresh(&opts).unwrap(), 0);
+        fs::remove_file(dir.join("broken.png")).unwrap();
+        reloaded.refresh(&opts).unwrap();
+        assert!(reloaded.is_empty());
+    }
+}
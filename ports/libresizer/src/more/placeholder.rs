@@ -0,0 +1,334 @@
+use super::super::*;
+use image::RgbaImage;
+use std::f32::consts::PI;
+use std::fs;
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+/// The hashes only keep the low frequencies, a small copy of the original is enough
+const HASH_SIZE: u32 = 64;
+/// ThumbHash is defined for images of at most 100x100
+const THUMBHASH_SIZE: u32 = 100;
+const LQIP_SIZE: u32 = 16;
+
+/// Placeholders shown while the real image is loading
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placeholder {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    /// Base64 encoded ThumbHash
+    pub thumbhash: String,
+    /// A tiny image as a `data:` URI
+    pub lqip: String,
+}
+
+impl Placeholder {
+    fn from_image(img: &DynamicImage, x_components: u32, y_components: u32) -> Result<Self> {
+        let (width, height) = img.dimensions();
+        let small = img.resize(HASH_SIZE, HASH_SIZE, FilterType::Triangle);
+        Ok(Placeholder {
+            width,
+            height,
+            blurhash: blurhash(&small, x_components, y_components)?,
+            thumbhash: base64::encode(&thumbhash(&small)),
+            lqip: lqip(img)?,
+        })
+    }
+
+    fn to_lines(&self) -> String {
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n",
+            self.width, self.height, self.blurhash, self.thumbhash, self.lqip
+        )
+    }
+
+    fn from_lines(lines: &str) -> Result<Self> {
+        let mut lines = lines.lines();
+        let mut next = || lines.next().ok_or(err_msg("Truncated placeholder cache"));
+        Ok(Placeholder {
+            width: next()?.parse()?,
+            height: next()?.parse()?,
+            blurhash: next()?.to_string(),
+            thumbhash: next()?.to_string(),
+            lqip: next()?.to_string(),
+        })
+    }
+}
+
+/// The placeholders of an original, cached in the output directory until the original changes
+pub fn placeholder(
+    opts: &ImageOption,
+    fname: &str,
+    x_components: u32,
+    y_components: u32,
+) -> Result<Placeholder> {
+    if x_components < 1 || x_components > 9 || y_components < 1 || y_components > 9 {
+        return Err(invalid_param("BlurHash components must be between 1 and 9"));
+    }
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(fname);
+    let mut cpath = PathBuf::from(&opts.output_dir());
+    cpath.push("placeholders");
+    cpath.push(format!("{}.{}x{}", fname, x_components, y_components));
+    let modified = fs::metadata(&fpath)?.modified()?;
+    if let Ok(cached) = fs::metadata(&cpath) {
+        if cached.modified()? >= modified {
+            return Placeholder::from_lines(&fs::read_to_string(&cpath)?);
+        }
+    }
+    let img = image::load_from_memory(&fs::read(&fpath)?)?;
+    let placeholder = Placeholder::from_image(&img, x_components, y_components)?;
+    fs::create_dir_all(cpath.parent().ok_or(err_msg("Illegal placeholder path"))?)?;
+    fs::write(&cpath, placeholder.to_lines())?;
+    Ok(placeholder)
+}
+
+/// Encode the image as a BlurHash string, see https://blurha.sh
+pub fn blurhash(img: &DynamicImage, x_components: u32, y_components: u32) -> Result<String> {
+    if x_components < 1 || x_components > 9 || y_components < 1 || y_components > 9 {
+        return Err(invalid_param("BlurHash components must be between 1 and 9"));
+    }
+    let rgba = img.to_rgba();
+    let (width, height) = rgba.dimensions();
+    let linear: Vec<[f32; 3]> = rgba
+        .pixels()
+        .map(|p| {
+            [
+                color::srgb_to_linear(f32::from(p.data[0]) / 255.0),
+                color::srgb_to_linear(f32::from(p.data[1]) / 255.0),
+                color::srgb_to_linear(f32::from(p.data[2]) / 255.0),
+            ]
+        })
+        .collect();
+    let mut factors = Vec::with_capacity((x_components * y_components) as usize);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0f32; 3];
+            for y in 0..height {
+                let basis_y = (PI * j as f32 * y as f32 / height as f32).cos();
+                for x in 0..width {
+                    let basis = basis_y * (PI * i as f32 * x as f32 / width as f32).cos();
+                    let pixel = linear[(y * width + x) as usize];
+                    for c in 0..3 {
+                        factor[c] += basis * pixel[c];
+                    }
+                }
+            }
+            let scale = normalisation / (width * height) as f32;
+            factors.push([factor[0] * scale, factor[1] * scale, factor[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    encode83(&mut hash, (x_components - 1) + (y_components - 1) * 9, 1);
+    let ac = &factors[1..];
+    let maximum = if ac.is_empty() {
+        encode83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual = ac
+            .iter()
+            .flat_map(|f| f.iter())
+            .fold(0f32, |max, v| max.max(v.abs()));
+        let quantised = ((actual * 166.0 - 0.5).floor()).max(0.0).min(82.0);
+        encode83(&mut hash, quantised as u32, 1);
+        (quantised + 1.0) / 166.0
+    };
+    let dc = factors[0];
+    let srgb = |v: f32| (color::linear_to_srgb(v.max(0.0).min(1.0)) * 255.0 + 0.5) as u32;
+    let dc_value = (srgb(dc[0]) << 16) + (srgb(dc[1]) << 8) + srgb(dc[2]);
+    encode83(&mut hash, dc_value, 4);
+    for factor in ac {
+        let quant = |v: f32| {
+            (sign_pow(v / maximum, 0.5) * 9.0 + 9.5)
+                .floor()
+                .max(0.0)
+                .min(18.0) as u32
+        };
+        let value = quant(factor[0]) * 19 * 19 + quant(factor[1]) * 19 + quant(factor[2]);
+        encode83(&mut hash, value, 2);
+    }
+    Ok(hash)
+}
+
+/// Encode the image as ThumbHash bytes, see https://evanw.github.io/thumbhash/
+pub fn thumbhash(img: &DynamicImage) -> Vec<u8> {
+    let rgba: RgbaImage = if img.width() > THUMBHASH_SIZE || img.height() > THUMBHASH_SIZE {
+        img.resize(THUMBHASH_SIZE, THUMBHASH_SIZE, FilterType::Triangle)
+            .to_rgba()
+    } else {
+        img.to_rgba()
+    };
+    let (w, h) = rgba.dimensions();
+    let pixels: Vec<[f32; 4]> = rgba
+        .pixels()
+        .map(|p| {
+            let alpha = f32::from(p.data[3]) / 255.0;
+            [
+                alpha / 255.0 * f32::from(p.data[0]),
+                alpha / 255.0 * f32::from(p.data[1]),
+                alpha / 255.0 * f32::from(p.data[2]),
+                alpha,
+            ]
+        })
+        .collect();
+
+    // The average colour, weighted by alpha
+    let mut avg = [0f32; 4];
+    for p in &pixels {
+        for c in 0..4 {
+            avg[c] += p[c];
+        }
+    }
+    if avg[3] > 0.0 {
+        for c in 0..3 {
+            avg[c] /= avg[3];
+        }
+    }
+    let has_alpha = avg[3] < (w * h) as f32;
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let longest = w.max(h) as f32;
+    let lx = ((l_limit * w as f32 / longest).round() as u32).max(1);
+    let ly = ((l_limit * h as f32 / longest).round() as u32).max(1);
+
+    // Luminance, yellow - blue, red - green and alpha, composited atop the average colour
+    let mut l = Vec::with_capacity(pixels.len());
+    let mut p = Vec::with_capacity(pixels.len());
+    let mut q = Vec::with_capacity(pixels.len());
+    let mut a = Vec::with_capacity(pixels.len());
+    for px in &pixels {
+        let alpha = px[3];
+        let r = avg[0] * (1.0 - alpha) + px[0];
+        let g = avg[1] * (1.0 - alpha) + px[1];
+        let b = avg[2] * (1.0 - alpha) + px[2];
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    let (l_dc, l_ac, l_scale) = thumbhash_channel(&l, w, h, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = thumbhash_channel(&p, w, h, 3, 3);
+    let (q_dc, q_ac, q_scale) = thumbhash_channel(&q, w, h, 3, 3);
+    let is_landscape = w > h;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if is_landscape { ly } else { lx })
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | (is_landscape as u32) << 15;
+    let mut hash = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+    let mut channels = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = thumbhash_channel(&a, w, h, 5, 5);
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+        channels.push(a_ac);
+    }
+    // Two factors of 4 bits per byte
+    let start = hash.len();
+    for (i, f) in channels.iter().flat_map(|ac| ac.iter()).enumerate() {
+        if start + i / 2 >= hash.len() {
+            hash.push(0);
+        }
+        hash[start + i / 2] |= ((15.0 * f).round() as u8) << ((i & 1) * 4);
+    }
+    hash
+}
+
+/// DCT of a channel into the DC term, the normalized AC terms and their scale
+fn thumbhash_channel(channel: &[f32], w: u32, h: u32, nx: u32, ny: u32) -> (f32, Vec<f32>, f32) {
+    let mut dc = 0.0;
+    let mut ac = Vec::new();
+    let mut scale = 0f32;
+    for cy in 0..ny {
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            let mut f = 0.0;
+            for y in 0..h {
+                let fy = (PI / h as f32 * cy as f32 * (y as f32 + 0.5)).cos();
+                for x in 0..w {
+                    let fx = (PI / w as f32 * cx as f32 * (x as f32 + 0.5)).cos();
+                    f += channel[(x + y * w) as usize] * fx * fy;
+                }
+            }
+            f /= (w * h) as f32;
+            if cx > 0 || cy > 0 {
+                ac.push(f);
+                scale = scale.max(f.abs());
+            } else {
+                dc = f;
+            }
+            cx += 1;
+        }
+    }
+    if scale > 0.0 {
+        for f in ac.iter_mut() {
+            *f = 0.5 + 0.5 / scale * *f;
+        }
+    }
+    (dc, ac, scale)
+}
+
+/// A tiny copy of the image, PNG when it has transparency and JPEG otherwise
+fn lqip(img: &DynamicImage) -> Result<String> {
+    let small = img.resize(LQIP_SIZE, LQIP_SIZE, FilterType::Triangle);
+    let has_alpha = small.to_rgba().pixels().any(|p| p.data[3] < 255);
+    let (format, mime) = if has_alpha {
+        (ImageOutputFormat::PNG, "image/png")
+    } else {
+        (ImageOutputFormat::JPEG(50), "image/jpeg")
+    };
+    let mut encoded = Vec::new();
+    small.write_to(&mut encoded, format)?;
+    Ok(format!("data:{};base64,{}", mime, base64::encode(&encoded)))
+}
+
+fn encode83(hash: &mut String, value: u32, length: u32) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        hash.push(BASE83[digit as usize] as char);
+    }
+}
+
+fn sign_pow(v: f32, exp: f32) -> f32 {
+    let pow = v.abs().powf(exp);
+    if v < 0.0 {
+        -pow
+    } else {
+        pow
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placeholder() {
+        let white = DynamicImage::ImageRgb8(image::ImageBuffer::from_pixel(
+            8,
+            8,
+            image::Rgb {
+                data: [255, 255, 255],
+            },
+        ));
+        assert!(blurhash(&white, 1, 1).unwrap().starts_with("00"));
+        // 27 luminance and 2 x 5 chroma factors, two per byte
+        assert_eq!(thumbhash(&white).len(), 5 + 19);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let first = placeholder(&opts, "ferris.png", 4, 3).unwrap();
+        assert_eq!(first.blurhash.len(), 6 + 2 * 11);
+        assert_eq!(placeholder(&opts, "ferris.png", 4, 3).unwrap(), first);
+    }
+}
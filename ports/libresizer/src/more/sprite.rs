@@ -0,0 +1,183 @@
+use super::super::*;
+use super::compose::{check_fname, MAX_CANVAS};
+use image::{imageops, ImageBuffer, Rgba, RgbaImage};
+
+pub const MAX_SPRITES: usize = 256;
+
+/// Where an original ended up in the sheet, after being fitted into its cell
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sprite {
+    pub fname: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct SpriteSheet {
+    /// The output file name, like the other outputs
+    pub hash: u64,
+    pub width: u32,
+    pub height: u32,
+    pub sprites: Vec<Sprite>,
+}
+
+impl SpriteSheet {
+    /// One class per sprite, named after the original without its extension
+    pub fn css(&self, url: &str, prefix: &str) -> String {
+        let mut css = format!(
+            ".{} {{ background-image: url(\"{}\"); background-repeat: no-repeat; }}\n",
+            prefix, url
+        );
+        for sprite in &self.sprites {
+            let name = sprite
+                .fname
+                .rsplitn(2, '.')
+                .last()
+                .unwrap_or(&sprite.fname)
+                .replace(
+                    |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_',
+                    "-",
+                );
+            css.push_str(&format!(
+                ".{}-{} {{ width: {}px; height: {}px; background-position: -{}px -{}px; }}\n",
+                prefix, name, sprite.width, sprite.height, sprite.x, sprite.y
+            ));
+        }
+        css
+    }
+}
+
+/// Cells, padding and background of a sheet
+#[derive(Debug, Clone, Hash, PartialEq)]
+pub struct SheetOption {
+    pub cell_width: u32,
+    pub cell_height: u32,
+    pub columns: Option<u32>,
+    pub padding: u32,
+    /// Transparent for sprite sheets, usually opaque for contact sheets
+    pub background: [u8; 4],
+}
+
+/// Fit every original into a cell with `Resizer`, centered, and save the sheet like the
+/// other outputs
+pub fn sprite(
+    opts: &ImageOption,
+    fnames: &[String],
+    sheet_opts: &SheetOption,
+    format_s: &str,
+) -> Result<SpriteSheet> {
+    if fnames.is_empty() || fnames.len() > MAX_SPRITES {
+        return Err(invalid_param(format!(
+            "A sheet has between 1 and {} images",
+            MAX_SPRITES
+        )));
+    }
+    let (cell_w, cell_h, padding) = (
+        sheet_opts.cell_width,
+        sheet_opts.cell_height,
+        sheet_opts.padding,
+    );
+    if cell_w == 0 || cell_h == 0 {
+        return Err(invalid_param("Illegal cell size"));
+    }
+    let count = fnames.len() as u32;
+    // As square as possible by default
+    let columns = sheet_opts
+        .columns
+        .unwrap_or((f64::from(count)).sqrt().ceil() as u32)
+        .max(1)
+        .min(count);
+    let rows = (count + columns - 1) / columns;
+    let span = |n: u32, size: u32| u64::from(n) * (u64::from(size) + 2 * u64::from(padding));
+    let (width, height) = (span(columns, cell_w), span(rows, cell_h));
+    if width > u64::from(MAX_CANVAS) || height > u64::from(MAX_CANVAS) {
+        return Err(invalid_param(format!(
+            "The sheet must be at most {} pixels wide and high",
+            MAX_CANVAS
+        )));
+    }
+    let mut sheet: RgbaImage = ImageBuffer::from_pixel(
+        width as u32,
+        height as u32,
+        Rgba {
+            data: sheet_opts.background,
+        },
+    );
+    let mut sprites = Vec::with_capacity(fnames.len());
+    for (i, fname) in fnames.iter().enumerate() {
+        check_fname(fname)?;
+        let mut fpath = PathBuf::from(&opts.input_dir());
+        fpath.push(fname);
+        let img = image::load_from_memory(&std::fs::read(&fpath)?)?;
+        let (w, h) = img.dimensions();
+        // Constrain the limiting side only, the aspect ratio is kept
+        let resizer = if u64::from(w) * u64::from(cell_h) >= u64::from(h) * u64::from(cell_w) {
+            Resizer {
+                width: Some(cell_w),
+                height: None,
+                filter_type: opts.filter_type(),
+                linear: opts.linear_light(),
+            }
+        } else {
+            Resizer {
+                width: None,
+                height: Some(cell_h),
+                filter_type: opts.filter_type(),
+                linear: opts.linear_light(),
+            }
+        };
+        let fitted = resizer.handle(img)?.to_rgba();
+        let (fw, fh) = (fitted.width().min(cell_w), fitted.height().min(cell_h));
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = column * (cell_w + 2 * padding) + padding + (cell_w - fw) / 2;
+        let y = row * (cell_h + 2 * padding) + padding + (cell_h - fh) / 2;
+        imageops::overlay(&mut sheet, &fitted, x, y);
+        sprites.push(Sprite {
+            fname: fname.clone(),
+            x,
+            y,
+            width: fw,
+            height: fh,
+        });
+    }
+    let result_img = DynamicImage::ImageRgba8(sheet);
+    let mut hasher = DefaultHasher::new();
+    hasher.write(&result_img.raw_pixels());
+    let mut encoded = Vec::new();
+    result_img.write_to(&mut encoded, output_format(format_s)?)?;
+    let hash = save_output(opts, hasher, Metadata::default(), encoded, format_s)?;
+    Ok(SpriteSheet {
+        hash,
+        width: width as u32,
+        height: height as u32,
+        sprites,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sprite() {
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        let fnames = vec!["ferris.png".to_string(); 5];
+        let sheet_opts = SheetOption {
+            cell_width: 64,
+            cell_height: 64,
+            columns: None,
+            padding: 1,
+            background: [0, 0, 0, 0],
+        };
+        let sheet = sprite(&opts, &fnames, &sheet_opts, "png").unwrap();
+        assert_eq!((sheet.width, sheet.height), (198, 132));
+        let last = &sheet.sprites[4];
+        assert_eq!(last.width, 64);
+        assert!(last.x >= 67 && last.y >= 67 && last.y + last.height <= 131);
+        let css = sheet.css("/sprite/sheet.png", "icon");
+        assert!(css.contains(".icon-ferris { width: 64px;"));
+        println!("{}", css);
+    }
+}
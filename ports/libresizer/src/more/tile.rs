@@ -0,0 +1,172 @@
+use super::super::*;
+use std::fs;
+use std::io;
+
+/// Tile edge length, without the overlap
+pub const TILE_SIZE: u32 = 254;
+/// Pixels shared with the neighbouring tiles
+pub const TILE_OVERLAP: u32 = 1;
+
+/// Path of a tile of the Deep Zoom pyramid, generating the pyramid on first use
+pub fn tile(
+    opts: &ImageOption,
+    fname: &str,
+    format: &str,
+    level: u32,
+    col: u32,
+    row: u32,
+) -> Result<PathBuf> {
+    let mut tpath = tiles_dir(opts, fname)?;
+    tpath.push(level.to_string());
+    tpath.push(format!("{}_{}.{}", col, row, format));
+    if !tpath.exists() && !descriptor_path(opts, fname, format)?.exists() {
+        generate(opts, fname, format)?;
+    }
+    if tpath.exists() {
+        Ok(tpath)
+    } else {
+        Err(Error::from(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No such tile",
+        )))
+    }
+}
+
+/// The DZI descriptor of the pyramid, generating the pyramid on first use
+pub fn descriptor(opts: &ImageOption, fname: &str, format: &str) -> Result<String> {
+    let dpath = descriptor_path(opts, fname, format)?;
+    if !dpath.exists() {
+        generate(opts, fname, format)?;
+    }
+    Ok(fs::read_to_string(dpath)?)
+}
+
+/// Whether the pyramid of the current version of an original is complete, its tiles are then
+/// served as they are
+pub fn generated(opts: &ImageOption, fname: &str, format: &str) -> bool {
+    descriptor_path(opts, fname, format)
+        .map(|dpath| dpath.exists())
+        .unwrap_or(false)
+}
+
+/// Number of the full resolution level, level 0 is a single pixel
+pub fn max_level(width: u32, height: u32) -> u32 {
+    let max = u64::from(width.max(height)).max(1);
+    64 - (max - 1).leading_zeros()
+}
+
+/// The pyramids of an original, one directory per version of the original
+fn pyramids_dir(opts: &ImageOption, fname: &str) -> PathBuf {
+    let mut dir = PathBuf::from(&opts.output_dir());
+    dir.push("tiles");
+    dir.push(fname);
+    dir
+}
+
+/// The pyramids of the current version of an original, keyed on its size and modification time
+/// like `cache_key`
+fn tiles_dir(opts: &ImageOption, fname: &str) -> Result<PathBuf> {
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(fname);
+    let metadata = fs::metadata(&fpath)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    let mut s = StableHasher::new();
+    metadata.len().hash(&mut s);
+    modified.hash(&mut s);
+    let mut dir = pyramids_dir(opts, fname);
+    dir.push(format!("{:016x}", s.finish()));
+    Ok(dir)
+}
+
+fn descriptor_path(opts: &ImageOption, fname: &str, format: &str) -> Result<PathBuf> {
+    let mut dpath = tiles_dir(opts, fname)?;
+    dpath.push(format!("{}.dzi", format));
+    Ok(dpath)
+}
+
+fn generate(opts: &ImageOption, fname: &str, format: &str) -> Result<()> {
+    let mut fpath = PathBuf::from(&opts.input_dir());
+    fpath.push(fname);
+    let img = image::load_from_memory(&fs::read(&fpath)?)?;
+    let output = output_format(format)?;
+    let (width, height) = img.dimensions();
+    let dir = tiles_dir(opts, fname)?;
+    // The pyramids of the previous versions are not served anymore
+    if let Ok(entries) = fs::read_dir(pyramids_dir(opts, fname)) {
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() && path != dir {
+                let _ = fs::remove_dir_all(path);
+            } else if path.is_file() {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+    let mut level_img = img;
+    for level in (0..=max_level(width, height)).rev() {
+        let (lw, lh) = level_img.dimensions();
+        let mut level_dir = dir.clone();
+        level_dir.push(level.to_string());
+        fs::create_dir_all(&level_dir)?;
+        for col in 0..(lw + TILE_SIZE - 1) / TILE_SIZE {
+            for row in 0..(lh + TILE_SIZE - 1) / TILE_SIZE {
+                let (x, tw) = tile_span(col, lw);
+                let (y, th) = tile_span(row, lh);
+                let tile = level_img.crop(x, y, tw, th);
+                let mut encoded = Vec::new();
+                tile.write_to(&mut encoded, output.clone())?;
+                let mut tpath = level_dir.clone();
+                tpath.push(format!("{}_{}.{}", col, row, format));
+                fs::write(tpath, encoded)?;
+            }
+        }
+        if level > 0 {
+            level_img = level_img.resize_exact(
+                ((lw + 1) / 2).max(1),
+                ((lh + 1) / 2).max(1),
+                opts.filter_type(),
+            );
+        }
+    }
+    // Written last, its presence marks a complete pyramid
+    let dzi = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" \
+         Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\
+         <Size Width=\"{}\" Height=\"{}\"/></Image>\n",
+        format, TILE_OVERLAP, TILE_SIZE, width, height
+    );
+    fs::write(descriptor_path(opts, fname, format)?, dzi)?;
+    Ok(())
+}
+
+/// Start and length of a tile along one axis, overlap included
+fn tile_span(index: u32, length: u32) -> (u32, u32) {
+    let start = if index == 0 {
+        0
+    } else {
+        index * TILE_SIZE - TILE_OVERLAP
+    };
+    let end = ((index + 1) * TILE_SIZE + TILE_OVERLAP).min(length);
+    (start, end - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile() {
+        assert_eq!(max_level(1, 1), 0);
+        assert_eq!(max_level(1024, 600), 10);
+        assert_eq!(max_level(1025, 600), 11);
+        let opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        println!("dzi: {}", descriptor(&opts, "ferris.png", "png").unwrap());
+        assert!(generated(&opts, "ferris.png", "png"));
+        println!("tile: {:?}", tile(&opts, "ferris.png", "png", 0, 0, 0).unwrap());
+        assert!(tiles_dir(&opts, "ferris.png")
+            .unwrap()
+            .starts_with(pyramids_dir(&opts, "ferris.png")));
+        assert!(!generated(&opts, "missing.png", "png"));
+    }
+}
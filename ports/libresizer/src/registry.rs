@@ -0,0 +1,122 @@
+use super::*;
+use std::collections::HashMap;
+
+/// Builds a handler from the value of its operation, e.g. `8` for `px:8`
+pub type HandlerFactory = Fn(&str) -> Result<Box<ImageHandler>> + Send + Sync;
+
+/// Operations added to the chains of `more::chain` by downstream crates, by key
+#[derive(Default)]
+pub struct HandlerRegistry {
+    factories: HashMap<String, Box<HandlerFactory>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        HandlerRegistry::default()
+    }
+
+    /// Make `key:value` operations build handlers with `factory`. Keys are lowercase ASCII
+    /// letters and the built-in operations can not be replaced.
+    pub fn register<F>(&mut self, key: &str, factory: F) -> Result<&mut Self>
+    where
+        F: Fn(&str) -> Result<Box<ImageHandler>> + Send + Sync + 'static,
+    {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_lowercase()) {
+            return Err(err_msg(format!("Illegal operation key: {}", key)));
+        }
+        if more::chain::BUILTIN_KEYS.contains(&key) {
+            return Err(err_msg(format!("Built-in operation: {}", key)));
+        }
+        self.factories.insert(key.to_string(), Box::new(factory));
+        Ok(self)
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.factories.contains_key(key)
+    }
+
+    pub fn build(&self, key: &str, value: &str) -> Result<Box<ImageHandler>> {
+        let factory = self
+            .factories
+            .get(key)
+            .ok_or(invalid_param(format!("Unknown operation: {}", key)))?;
+        factory(value)
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.factories.keys().map(|k| k.as_str()).collect();
+        keys.sort();
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Invert;
+
+    impl ImageHandler for Invert {
+        fn name(&self) -> &str {
+            "invert"
+        }
+
+        fn handle(&self, mut img: DynamicImage) -> Result<DynamicImage> {
+            img.invert();
+            Ok(img)
+        }
+    }
+
+    /// Configured by the downstream crate, not by the URL
+    struct Tint(&'static str);
+
+    impl ImageHandler for Tint {
+        fn name(&self) -> &str {
+            "tint"
+        }
+
+        fn cache_key(&self) -> String {
+            self.0.to_string()
+        }
+
+        fn handle(&self, img: DynamicImage) -> Result<DynamicImage> {
+            Ok(img)
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = HandlerRegistry::new();
+        registry
+            .register("inv", |_| Ok(Box::new(Invert) as Box<ImageHandler>))
+            .unwrap();
+        assert!(registry
+            .register("bl", |_| Ok(Box::new(Invert) as Box<ImageHandler>))
+            .is_err());
+        assert_eq!(registry.keys(), vec!["inv"]);
+        assert_eq!(registry.build("inv", "").unwrap().name(), "invert");
+        let spec = TransformSpec::from_path("w:200,inv:", &registry).unwrap();
+        let mut opts = ImageOption::new("../../originals", "../../outputs", FilterType::Lanczos3);
+        opts.handlers(registry);
+        let mut img_info = ImageInfo::new("ferris", "png", None, None);
+        img_info.transform(spec);
+        println!("hash: {}", resize(&opts, &img_info).unwrap());
+        // Same operation, other parameters
+        let keys: Vec<u64> = ["red", "blue"]
+            .iter()
+            .map(|color| {
+                let mut registry = HandlerRegistry::new();
+                registry
+                    .register("tint", move |_| {
+                        Ok(Box::new(Tint(color)) as Box<ImageHandler>)
+                    })
+                    .unwrap();
+                let spec = TransformSpec::from_path("w:200,tint:", &registry).unwrap();
+                opts.handlers(registry);
+                img_info.transform(spec);
+                cache_key(&opts, &img_info).unwrap()
+            })
+            .collect();
+        assert_ne!(keys[0], keys[1]);
+    }
+}
@@ -0,0 +1,231 @@
+use self::more::chain::{gen_operation, gen_steps, MAX_OPERATIONS};
+use super::*;
+use serde_derive::{Deserialize, Serialize};
+use std::mem;
+
+const FORMAT_KEY: &str = "fmt";
+const QUALITY_KEY: &str = "jq";
+const OPERATIONS_KEY: &str = "ops";
+
+/// Ordered operations and output options of a transformation, the same for the URLs, the
+/// presets and the clients. It round-trips through three forms:
+///
+/// * path: the `/display/t` chain, `w:400,bl:3,fmt:jpg,jq:80`
+/// * query: `ops=w:400,bl:3&fmt=jpg&jq=80`
+/// * JSON: `{"operations":[{"op":"resize","value":{"width":400,"height":null}},{"op":"blur","value":3}],"format":"jpg","quality":80}`
+#[derive(Debug, Clone, Default, Hash, PartialEq, Serialize, Deserialize)]
+pub struct TransformSpec {
+    #[serde(default)]
+    pub operations: Vec<Operation>,
+    /// Output format, the one of the original by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// JPEG quality, from 1 to 100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality: Option<u8>,
+}
+
+impl TransformSpec {
+    pub fn new(operations: Vec<Operation>) -> Self {
+        TransformSpec {
+            operations,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_path(path_s: &str, registry: &HandlerRegistry) -> Result<Self> {
+        let mut spec = TransformSpec::default();
+        for step in gen_steps(path_s)? {
+            let (key, value) = step.split_at(step.find(':').unwrap_or(0));
+            let value = &value[1..];
+            match key {
+                FORMAT_KEY => spec.format = Some(value.to_string()),
+                QUALITY_KEY => {
+                    spec.quality = Some(
+                        value
+                            .parse()
+                            .map_err(|_| invalid_param(format!("Illegal quality: {}", value)))?,
+                    )
+                }
+                _ => spec.operations.push(gen_operation(&step, registry)?),
+            }
+        }
+        spec.check(registry)?;
+        Ok(spec)
+    }
+
+    pub fn to_path(&self) -> String {
+        let mut steps: Vec<String> = self.operations.iter().map(|op| op.to_string()).collect();
+        if let Some(format) = &self.format {
+            steps.push(format!("{}:{}", FORMAT_KEY, format));
+        }
+        if let Some(quality) = self.quality {
+            steps.push(format!("{}:{}", QUALITY_KEY, quality));
+        }
+        steps.join(",")
+    }
+
+    /// Parameters other than `ops`, `fmt` and `jq` are left to the caller
+    pub fn from_query(query_s: &str, registry: &HandlerRegistry) -> Result<Self> {
+        let mut steps = Vec::new();
+        for pair in query_s.split('&').filter(|pair| !pair.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let key = decode(kv.next().unwrap_or(""))?;
+            let value = decode(kv.next().unwrap_or(""))?;
+            match key.as_str() {
+                OPERATIONS_KEY if !value.is_empty() => steps.push(value),
+                FORMAT_KEY | QUALITY_KEY => steps.push(format!("{}:{}", key, value)),
+                _ => (),
+            }
+        }
+        TransformSpec::from_path(&steps.join(","), registry)
+    }
+
+    pub fn to_query(&self) -> String {
+        let mut pairs = Vec::new();
+        if !self.operations.is_empty() {
+            let steps: Vec<String> = self.operations.iter().map(|op| op.to_string()).collect();
+            pairs.push(format!("{}={}", OPERATIONS_KEY, encode(&steps.join(","))));
+        }
+        if let Some(format) = &self.format {
+            pairs.push(format!("{}={}", FORMAT_KEY, encode(format)));
+        }
+        if let Some(quality) = self.quality {
+            pairs.push(format!("{}={}", QUALITY_KEY, quality));
+        }
+        pairs.join("&")
+    }
+
+    pub fn from_json(json_s: &str, registry: &HandlerRegistry) -> Result<Self> {
+        let spec: TransformSpec =
+            serde_json::from_str(json_s).map_err(|e| invalid_param(e.to_string()))?;
+        spec.check(registry)?;
+        Ok(spec)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Reject what the path form would not accept, whatever the spec was built from
+    pub fn check(&self, registry: &HandlerRegistry) -> Result<()> {
+        if self.operations.len() > MAX_OPERATIONS {
+            return Err(invalid_param(format!(
+                "A chain has at most {} operations",
+                MAX_OPERATIONS
+            )));
+        }
+        for operation in &self.operations {
+            let step = operation.to_string();
+            if gen_operation(&step, registry)? != *operation {
+                return Err(invalid_param(format!("Illegal operation: {}", step)));
+            }
+        }
+        if let Some(format) = &self.format {
+            mime_type(format)?;
+        }
+        if let Some(quality) = self.quality {
+            if quality < 1 || quality > 100 {
+                return Err(invalid_param("Quality must be between 1 and 100"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the operation of the same kind, or add it at the end
+    pub(crate) fn set(&mut self, operation: Operation) {
+        let kind = mem::discriminant(&operation);
+        match self
+            .operations
+            .iter_mut()
+            .find(|op| mem::discriminant(&**op) == kind)
+        {
+            Some(op) => *op = operation,
+            None => self.operations.push(operation),
+        }
+    }
+}
+
+fn decode(component_s: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(component_s.len());
+    let mut i = 0;
+    while i < component_s.len() {
+        match component_s.as_bytes()[i] {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = component_s
+                    .get(i + 1..i + 3)
+                    .ok_or(invalid_param("Illegal query string"))?;
+                bytes.push(
+                    u8::from_str_radix(hex, 16)
+                        .map_err(|_| invalid_param("Illegal query string"))?,
+                );
+                i += 2;
+            }
+            b => bytes.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_param("Illegal query string"))
+}
+
+fn encode(component_s: &str) -> String {
+    let mut encoded = String::with_capacity(component_s.len());
+    for c in component_s.chars() {
+        match c {
+            '%' | '&' | '=' | '+' | '#' | ' ' => encoded.push_str(&format!("%{:02X}", c as u8)),
+            _ => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec() {
+        let registry = HandlerRegistry::new();
+        let spec = TransformSpec::from_path(
+            "cr:x10y0w300,s:200x200,cv:k0,-1,0,-1,5,-1,0,-1,0,fx:shadow,x4,c000000,fmt:jpg,jq:80",
+            &registry,
+        )
+        .unwrap();
+        assert_eq!(spec.operations.len(), 4);
+        assert_eq!(spec.format, Some("jpg".to_string()));
+        assert_eq!(spec.quality, Some(80));
+        assert_eq!(
+            TransformSpec::from_path(&spec.to_path(), &registry).unwrap(),
+            spec
+        );
+        assert_eq!(
+            TransformSpec::from_query(&spec.to_query(), &registry).unwrap(),
+            spec
+        );
+        let json = spec.to_json().unwrap();
+        assert!(json.starts_with(
+            r#"{"operations":[{"op":"crop","value":{"x":10,"y":0,"width":300,"height":null}}"#
+        ));
+        assert_eq!(TransformSpec::from_json(&json, &registry).unwrap(), spec);
+        let json =
+            r#"{"operations":[{"op":"resize","value":{"width":400}},{"op":"blur","value":3}]}"#;
+        assert_eq!(
+            TransformSpec::from_json(json, &registry).unwrap().to_path(),
+            "w:400,bl:3"
+        );
+        let query = TransformSpec::from_query("ops=w%3A400%2Cbl%3A3&dpr=2", &registry).unwrap();
+        assert_eq!(query.to_path(), "w:400,bl:3");
+        assert!(TransformSpec::from_path("jq:0", &registry).is_err());
+        assert!(TransformSpec::from_json(
+            r#"{"operations":[{"op":"rotate","value":"45"}]}"#,
+            &registry
+        )
+        .is_err());
+        assert!(TransformSpec::from_json(
+            r#"{"operations":[{"op":"custom","value":{"key":"bl","value":"3"}}]}"#,
+            &registry
+        )
+        .is_err());
+    }
+}
@@ -56,4 +56,61 @@ pub fn build_cli() -> App<'static, 'static> {
                 .required(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("keep_metadata")
+                .long("keep-metadata")
+                .help("Keep EXIF metadata of the original in outputs")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("icc_policy")
+                .long("icc")
+                .help("ICC profile handling (keep, strip or convert)")
+                .required(false)
+                .default_value("keep")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("icc_target")
+                .long("icc-target")
+                .help("ICC profile file to convert into, defaults to sRGB")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("linear_light")
+                .long("linear-light")
+                .help("Resample in linear light instead of gamma-encoded sRGB")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("depth_policy")
+                .long("depth")
+                .help("16-bit originals handling (preserve or dither)")
+                .required(false)
+                .default_value("dither")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("breakpoints")
+                .long("breakpoints")
+                .help("Widths of a srcset preset, as name=320,640,960 (repeatable)")
+                .required(false)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client_hints")
+                .long("client-hints")
+                .help("Size images from the DPR/Width/Save-Data Client Hints headers")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("presets")
+                .long("presets")
+                .help("TOML file of named operation lists for /display/p")
+                .required(false)
+                .takes_value(true),
+        )
 }
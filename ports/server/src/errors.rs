@@ -7,6 +7,8 @@ use actix_web::{error, http, HttpResponse};
 pub enum WebError {
     #[fail(display = "Internal error, reason: {}", _0)]
     InternalError(String),
+    #[fail(display = "Bad request, reason: {}", _0)]
+    BadRequest(String),
     #[fail(display = "Not Found")]
     NotFound,
 }
@@ -18,6 +20,9 @@ impl WebError {
                 return WebError::NotFound;
             }
         }
+        if let Some(e) = e.find_root_cause().downcast_ref::<InvalidParameter>() {
+            return WebError::BadRequest(e.0.clone());
+        }
         WebError::InternalError(e.to_string())
     }
 
@@ -37,6 +42,9 @@ impl error::ResponseError for WebError {
                 http::StatusCode::INTERNAL_SERVER_ERROR,
                 format!("{}", self),
             ),
+            WebError::BadRequest(_cause) => {
+                HttpResponse::with_body(http::StatusCode::BAD_REQUEST, format!("{}", self))
+            }
             WebError::NotFound => HttpResponse::new(http::StatusCode::NOT_FOUND),
         }
     }
@@ -1,9 +1,7 @@
-use actix_web::server;
 use env_logger::{Builder, Target};
-use irirserver::{cli, routes::*};
+use irirserver::{cli, presets::load_presets, routes::*};
 use lazy_static::lazy_static;
 use libresizer::ImageOption;
-use log::info;
 
 lazy_static! {
     static ref MATCHES: clap::ArgMatches<'static> = cli::build_cli().get_matches();
@@ -16,7 +14,29 @@ fn main() {
     let outputs = MATCHES.value_of("output_path").unwrap();
     let filter_type =
         libresizer::gen_filter_type(MATCHES.value_of("filter_type").unwrap()).unwrap();
-    let state = AppState::new(ImageOption::new(originals, outputs, filter_type));
+    let icc_policy = libresizer::gen_icc_policy(MATCHES.value_of("icc_policy").unwrap()).unwrap();
+    let depth_policy =
+        libresizer::gen_depth_policy(MATCHES.value_of("depth_policy").unwrap()).unwrap();
+    let mut options = ImageOption::new(originals, outputs, filter_type);
+    options
+        .metadata(!MATCHES.is_present("keep_metadata"))
+        .icc(icc_policy)
+        .linear(MATCHES.is_present("linear_light"))
+        .depth(depth_policy);
+    if let Some(icc_target) = MATCHES.value_of("icc_target") {
+        options.icc_target(icc_target);
+    }
+    let mut state = AppState::new(options);
+    state.client_hints(MATCHES.is_present("client_hints"));
+    if let Some(presets_path) = MATCHES.value_of("presets") {
+        state.presets(load_presets(presets_path, state.options.handler_registry()).unwrap());
+    }
+    if let Some(specs) = MATCHES.values_of("breakpoints") {
+        for spec in specs {
+            let (name, widths) = parse_breakpoints(spec).unwrap();
+            state.breakpoints(name.as_str(), widths);
+        }
+    }
 
     std::env::set_var("RUST_LOG", "actix_web=info,irirserver=info");
 
@@ -24,15 +44,5 @@ fn main() {
     log_bld.target(Target::Stdout);
 
     log_bld.init();
-    let apps = move || {
-        vec![
-            DisplayApp::new().action(state.clone()).finish(),
-            IndexApp::new().action(state.clone()).finish(),
-        ]
-    };
-
-    info!("Starting app……");
-    let bind_s = format!("{}:{}", host, port);
-    info!("http://{}", bind_s);
-    server::new(apps).bind(bind_s).unwrap().run();
+    serve(state, &format!("{}:{}", host, port));
 }
@@ -0,0 +1,74 @@
+use libcore::errors::*;
+use libresizer::{HandlerRegistry, TransformSpec};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+
+/// A named transformation, e.g. from `presets.toml`:
+///
+/// ```toml
+/// [thumb]
+/// operations = "s:200x200,cv:sharpen"
+/// format = "jpg"
+/// quality = 80
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct Preset {
+    /// Operations of the `/display/t` grammar
+    pub operations: String,
+    /// Output format, the one of the request by default
+    pub format: Option<String>,
+    /// JPEG quality, from 1 to 100
+    pub quality: Option<u8>,
+}
+
+/// Read and check the presets of a TOML file, one table per preset. Operations may use the
+/// custom handlers of `registry`.
+pub fn load_presets(
+    path: &str,
+    registry: &HandlerRegistry,
+) -> Result<HashMap<String, TransformSpec>> {
+    parse_presets(&std::fs::read_to_string(path)?, registry)
+}
+
+/// Like `load_presets`, for the content of the file
+pub fn parse_presets(
+    toml_s: &str,
+    registry: &HandlerRegistry,
+) -> Result<HashMap<String, TransformSpec>> {
+    let presets: HashMap<String, Preset> = toml::from_str(toml_s)?;
+    let mut specs = HashMap::new();
+    for (name, preset) in presets {
+        let mut spec = TransformSpec::from_path(&preset.operations, registry)
+            .map_err(|e| err_msg(format!("Preset {}: {}", name, e)))?;
+        spec.format = preset.format.or(spec.format);
+        spec.quality = preset.quality.or(spec.quality);
+        spec.check(registry)
+            .map_err(|e| err_msg(format!("Preset {}: {}", name, e)))?;
+        specs.insert(name, spec);
+    }
+    Ok(specs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_presets() {
+        let registry = HandlerRegistry::new();
+        let valid = r#"
+[thumb]
+operations = "s:200x200,cv:sharpen"
+format = "jpg"
+quality = 80
+"#;
+        let presets = parse_presets(valid, &registry).unwrap();
+        assert_eq!(
+            presets["thumb"].to_path(),
+            "s:200x200,cv:sharpen,fmt:jpg,jq:80"
+        );
+        let invalid = format!("{}[broken]\noperations = \"s:200x200,nope:1\"\n", valid);
+        let err = parse_presets(&invalid, &registry).unwrap_err();
+        assert!(err.to_string().starts_with("Preset broken: "));
+    }
+}
@@ -0,0 +1,139 @@
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{fs, App, HttpRequest};
+use lazy_static::lazy_static;
+use libcore::errors::*;
+use libresizer::more::compose::{self, Layout};
+use libresizer::more::effects::gen_color;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+const DEFAULT_CELL_SIZE: u32 = 300;
+
+lazy_static! {
+    static ref Composed: Mutex<HashMap<u64, u64>> = Mutex::new(HashMap::new());
+}
+
+fn compose_grid(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let query = req.query();
+    let fnames = get_images_params(req).map_err(WebError::internal)?;
+    let number = |key: &str, default: u32| -> WebResult<u32> {
+        match query.get(key) {
+            Some(value_s) => value_s.parse().map_err(WebError::parse),
+            None => Ok(default),
+        }
+    };
+    // As square as possible by default
+    let columns = (fnames.len() as f64).sqrt().ceil() as u32;
+    let layout = Layout::grid(
+        &fnames,
+        number("cols", columns)?,
+        number("w", DEFAULT_CELL_SIZE)?,
+        number("h", DEFAULT_CELL_SIZE)?,
+        number("gap", 0)?,
+        get_background_params(req).map_err(WebError::internal)?,
+    )
+    .map_err(WebError::internal)?;
+    compose_layout(req, &layout)
+}
+
+fn compose_cells(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let query = req.query();
+    let fnames = get_images_params(req).map_err(WebError::internal)?;
+    let size = |key: &str| -> WebResult<u32> {
+        query
+            .get(key)
+            .ok_or(err_msg(format!("Missing {} parameter", key)))
+            .map_err(WebError::internal)?
+            .parse()
+            .map_err(WebError::parse)
+    };
+    let cells_s = query
+        .get("cells")
+        .ok_or(err_msg("Missing cells parameter"))
+        .map_err(WebError::internal)?;
+    let cells = compose::gen_cells(&fnames, cells_s).map_err(WebError::internal)?;
+    let layout = Layout::new(
+        size("w")?,
+        size("h")?,
+        get_background_params(req).map_err(WebError::internal)?,
+        cells,
+    )
+    .map_err(WebError::internal)?;
+    compose_layout(req, &layout)
+}
+
+fn compose_layout(req: &HttpRequest<AppState>, layout: &Layout) -> WebResult<fs::NamedFile> {
+    let opts = &req.state().options;
+    let format = req
+        .match_info()
+        .get("format")
+        .ok_or(err_msg("Missing format parameter"))
+        .map_err(WebError::internal)?;
+    let mut hasher = DefaultHasher::new();
+    layout.hash(&mut hasher);
+    format.hash(&mut hasher);
+    let layout_hash = hasher.finish();
+    let mut composed = Composed.lock().unwrap();
+    let hash = match composed.get(&layout_hash) {
+        Some(hash) => *hash,
+        None => compose::compose(opts, layout, format).map_err(WebError::internal)?,
+    };
+    let mut opath = PathBuf::from(&opts.output_dir());
+    opath.push(hash.to_string());
+    opath.set_extension(format);
+    let nf = fs::NamedFile::open(
+        opath
+            .to_str()
+            .ok_or(err_msg("No output file found"))
+            .map_err(WebError::internal)?,
+    )
+    .map_err(WebError::io)?;
+    composed.insert(layout_hash, hash);
+    Ok(nf)
+}
+
+/// Comma separated file names of the originals, e.g. `images=a.jpg,b.png`
+pub(super) fn get_images_params(req: &HttpRequest<AppState>) -> Result<Vec<String>> {
+    let query = req.query();
+    let images_s = query
+        .get("images")
+        .ok_or(err_msg("Missing images parameter"))?;
+    Ok(images_s
+        .split(',')
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect())
+}
+
+/// `bg=rrggbb` or `bg=rrggbbaa`, white by default
+fn get_background_params(req: &HttpRequest<AppState>) -> Result<[u8; 4]> {
+    match req.query().get("bg") {
+        Some(bg_s) => gen_color(bg_s),
+        None => Ok([255, 255, 255, 255]),
+    }
+}
+
+pub struct ComposeApp {}
+
+impl ComposeApp {
+    pub fn new() -> Self {
+        ComposeApp {}
+    }
+}
+
+impl ActixApp for ComposeApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/compose")
+            .resource("/grid.{format}", |r| r.f(compose_grid))
+            .resource("/cells.{format}", |r| r.f(compose_cells))
+    }
+}
@@ -1,22 +1,29 @@
 use super::*;
 use crate::errors::*;
-use actix_web::middleware::Logger;
+use actix_web::middleware::{DefaultHeaders, Logger};
 use actix_web::{
     fs, http, middleware::ErrorHandlers, middleware::Response, App, HttpRequest, HttpResponse,
     Result as AtxResult,
 };
 use lazy_static::lazy_static;
 use libcore::errors::*;
-use libresizer::ImageInfo;
+use libresizer::more::chain::Operation;
+use libresizer::{ImageInfo, OutputIndex, TransformSpec};
+use log::warn;
 use regex::Regex;
-use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Mutex;
 
 type WebResult<T> = std::result::Result<T, WebError>;
 
+const MAX_SIZE: u32 = 1280;
+const MAX_DPR: f64 = 4.0;
+/// Hints requested from the browsers, with `Save-Data` they change the output
+const CLIENT_HINTS: &str = "Sec-CH-DPR, Sec-CH-Width, Sec-CH-Viewport-Width";
+
 lazy_static! {
-    static ref Cache: Mutex<HashMap<u64, u64>> = Mutex::new(HashMap::new());
+    /// Loaded by `load_cache`, or by the first request
+    static ref Cache: Mutex<Option<OutputIndex>> = Mutex::new(None);
 }
 
 fn render_display_404<S>(_: &HttpRequest<S>, mut resp: HttpResponse) -> AtxResult<Response> {
@@ -28,7 +35,8 @@ fn display_resize(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
     let (name, format) = get_file_params(req).map_err(WebError::internal)?;
     let (width, height) = get_size_params(req).map_err(WebError::internal)?;
     check_size(&width, &height).map_err(WebError::internal)?;
-    let img_info = ImageInfo::new(name.as_str(), format.as_str(), width, height);
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), width, height);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
     display(&req.state().options, &img_info)
 }
 
@@ -43,6 +51,7 @@ fn display_blur(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
     let (width, height) = get_size_params(req).map_err(WebError::internal)?;
     let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), width, height);
     img_info.blur(level);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
     display(&req.state().options, &img_info)
 }
 
@@ -56,55 +65,157 @@ fn display_crop(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
     };
     let crop_params = get_crop_params(req).map_err(WebError::internal)?;
     img_info.crop(crop_params.0, crop_params.1, crop_params.2, crop_params.3);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
+    display(&req.state().options, &img_info)
+}
+
+fn display_convolve(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let kernel_s = req
+        .match_info()
+        .get("kernel_s")
+        .ok_or(err_msg("Missing kernel parameter"))
+        .map_err(WebError::internal)?;
+    libresizer::more::convolve::gen_kernel(kernel_s).map_err(WebError::internal)?;
+    let (name, format) = get_file_params(req).map_err(WebError::internal)?;
+    let (width, height) = get_size_params(req).map_err(WebError::internal)?;
+    check_size(&width, &height).map_err(WebError::internal)?;
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), width, height);
+    img_info.kernel(kernel_s);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
+    display(&req.state().options, &img_info)
+}
+
+fn display_levels(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let levels_s = req
+        .match_info()
+        .get("levels_s")
+        .ok_or(err_msg("Missing levels parameter"))
+        .map_err(WebError::internal)?;
+    let levels = libresizer::more::levels::gen_levels(levels_s).map_err(WebError::internal)?;
+    let (name, format) = get_file_params(req).map_err(WebError::internal)?;
+    let (width, height) = get_size_params(req).map_err(WebError::internal)?;
+    check_size(&width, &height).map_err(WebError::internal)?;
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), width, height);
+    img_info.levels(levels);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
+    display(&req.state().options, &img_info)
+}
+
+fn display_effect(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let effect_s = req
+        .match_info()
+        .get("effect_s")
+        .ok_or(err_msg("Missing effect parameter"))
+        .map_err(WebError::internal)?;
+    let effect = libresizer::more::effects::gen_effect(effect_s).map_err(WebError::internal)?;
+    let (name, format) = get_file_params(req).map_err(WebError::internal)?;
+    let (width, height) = get_size_params(req).map_err(WebError::internal)?;
+    check_size(&width, &height).map_err(WebError::internal)?;
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), width, height);
+    img_info.effect(effect);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
     display(&req.state().options, &img_info)
 }
 
-fn display(opts: &ImageOption, img_info: &ImageInfo) -> WebResult<fs::NamedFile> {
-    let info_hash = img_info.to_hash();
+fn display_chain(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let ops_s = req
+        .match_info()
+        .get("ops_s")
+        .ok_or(err_msg("Missing operations parameter"))
+        .map_err(WebError::internal)?;
+    let spec = TransformSpec::from_path(ops_s, req.state().options.handler_registry())
+        .map_err(WebError::internal)?;
+    check_spec_sizes(&spec).map_err(WebError::internal)?;
+    let (name, format) = get_file_params(req).map_err(WebError::internal)?;
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), None, None);
+    img_info.transform(spec);
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
+    display(&req.state().options, &img_info)
+}
+
+fn display_preset(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let preset_s = req
+        .match_info()
+        .get("preset")
+        .ok_or(err_msg("Missing preset parameter"))
+        .map_err(WebError::internal)?;
+    let preset = req
+        .state()
+        .presets
+        .get(preset_s)
+        .ok_or(WebError::NotFound)?;
+    let (name, format) = get_file_params(req).map_err(WebError::internal)?;
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), None, None);
+    img_info.transform(preset.clone());
+    set_option_params(req, &mut img_info).map_err(WebError::internal)?;
+    display(&req.state().options, &img_info)
+}
+
+pub(super) fn display(opts: &ImageOption, img_info: &ImageInfo) -> WebResult<fs::NamedFile> {
+    let hash = process(opts, img_info, None)?;
+    let mut opath = PathBuf::from(&opts.output_dir());
+    opath.push(hash.to_string());
+    opath.set_extension(img_info.output_format());
+    Ok(fs::NamedFile::open(
+        opath
+            .to_str()
+            .ok_or(err_msg("No output file found"))
+            .map_err(WebError::internal)?,
+    )
+    .map_err(WebError::io)?)
+}
+
+/// Read the outputs of the previous runs, returns their number
+pub fn load_cache(opts: &ImageOption) -> Result<usize> {
+    let index = OutputIndex::load(opts.output_dir())?;
+    let len = index.len();
+    *Cache.lock().unwrap() = Some(index);
+    Ok(len)
+}
+
+/// The output of `img_info`, generated unless it is in the cache. `data` replaces the original
+/// for uploads.
+pub(super) fn process(
+    opts: &ImageOption,
+    img_info: &ImageInfo,
+    data: Option<&[u8]>,
+) -> WebResult<u64> {
+    let info_hash = if let Some(data) = data {
+        libresizer::cache_key_data(opts, img_info, data)
+    } else {
+        libresizer::cache_key(opts, img_info)
+    }
+    .map_err(WebError::internal)?;
     let mut cache = Cache.lock().unwrap();
+    if cache.is_none() {
+        *cache = Some(OutputIndex::load(opts.output_dir()).map_err(WebError::internal)?);
+    }
     // In the cache
-    if cache.contains_key(&info_hash) {
-        let hash = cache
-            .get(&info_hash)
-            .ok_or(err_msg("Cache temporary error"))
-            .map_err(WebError::internal)?;
-        let mut opath = PathBuf::from(&opts.output_dir());
-        opath.push(hash.to_string());
-        opath.set_extension(img_info.format());
-        Ok(fs::NamedFile::open(
-            opath
-                .to_str()
-                .ok_or(err_msg("No output file found"))
-                .map_err(WebError::internal)?,
-        )
-        .map_err(WebError::io)?)
+    if let Some(hash) = cache.as_mut().unwrap().get(info_hash) {
+        return Ok(hash);
+    }
+    // Handle without holding the cache, the other requests do not wait for it
+    drop(cache);
+    let hash = if let Some(data) = data {
+        libresizer::resize_data(&opts, &img_info, data).map_err(WebError::internal)?
+    } else if img_info.iiif_params().is_some() {
+        libresizer::more::iiif(&opts, &img_info).map_err(WebError::internal)?
     } else {
-        // Handle & Add to cache
-        let hash = {
-            if img_info.croped() {
-                libresizer::more::crop(&opts, &img_info).map_err(WebError::internal)?
-            } else if img_info.blur_level() != None {
-                libresizer::more::blur(&opts, &img_info).map_err(WebError::internal)?
-            } else {
-                libresizer::resize(&opts, &img_info).map_err(WebError::internal)?
-            }
-        };
-        let mut opath = PathBuf::from(&opts.output_dir());
-        opath.push(hash.to_string());
-        opath.set_extension(img_info.format());
-        let nf = fs::NamedFile::open(
-            opath
-                .to_str()
-                .ok_or(err_msg("No output file found"))
-                .map_err(WebError::internal)?,
-        )
-        .map_err(WebError::io)?;
-        cache.insert(img_info.to_hash(), hash);
-        Ok(nf)
+        libresizer::resize(&opts, &img_info).map_err(WebError::internal)?
+    };
+    // Add to cache
+    let mut cache = Cache.lock().unwrap();
+    if let Err(e) = cache
+        .as_mut()
+        .unwrap()
+        .insert(info_hash, hash, img_info.output_format())
+    {
+        warn!("Output index not updated: {}", e);
     }
+    Ok(hash)
 }
 
-fn get_file_params(req: &HttpRequest<AppState>) -> Result<(String, String)> {
+pub(super) fn get_file_params(req: &HttpRequest<AppState>) -> Result<(String, String)> {
     let params = req.match_info();
     let name = params
         .get("name")
@@ -191,7 +302,93 @@ fn get_blur_params(req: &HttpRequest<AppState>) -> Result<Option<u32>> {
     Ok(blur_l)
 }
 
+/// Size parameters scaled by the device pixel ratio, from `dpr` or the Client Hints
 fn get_size_params(req: &HttpRequest<AppState>) -> Result<(Option<u32>, Option<u32>)> {
+    let (width, height) = get_layout_size_params(req)?;
+    let hints = get_client_hints(req);
+    let mut dpr = if let Some(dpr_s) = req.query().get("dpr") {
+        dpr_s.parse::<f64>()?
+    } else {
+        hints.dpr.unwrap_or(1.0)
+    };
+    if !(dpr > 0.0 && dpr <= MAX_DPR) {
+        return Err(invalid_param("Illegal dpr parameter"));
+    }
+    if hints.save_data {
+        dpr = dpr.min(1.0);
+    }
+    if width.is_none() && height.is_none() {
+        if hints.width.is_none() && hints.viewport_width.is_none() {
+            return Ok((None, None));
+        }
+        let (name, _) = get_file_params(req)?;
+        let (original_width, _) = libresizer::dimensions(&req.state().options, name.as_str())?;
+        return Ok((hinted_width(&hints, dpr, original_width), None));
+    }
+    Ok(scale_size(width, height, dpr))
+}
+
+/// The width asked by the hints for a request without a size. They give the room available,
+/// not a size to upscale the original to.
+fn hinted_width(hints: &ClientHints, dpr: f64, original_width: u32) -> Option<u32> {
+    hints
+        .width
+        // Already in device pixels
+        .map(|w| w.min(MAX_SIZE))
+        .or_else(|| scale_size(hints.viewport_width, None, dpr).0)
+        .map(|w| w.min(original_width).max(1))
+}
+
+/// Scale the size by the ratio, upscaling no further than the size limit
+fn scale_size(width: Option<u32>, height: Option<u32>, dpr: f64) -> (Option<u32>, Option<u32>) {
+    let largest = width.unwrap_or(0).max(height.unwrap_or(0));
+    if largest == 0 {
+        return (width, height);
+    }
+    let ratio = if dpr > 1.0 {
+        dpr.min(f64::from(MAX_SIZE) / f64::from(largest)).max(1.0)
+    } else {
+        dpr
+    };
+    let scale = |v: u32| ((f64::from(v) * ratio).round() as u32).max(1);
+    (width.map(scale), height.map(scale))
+}
+
+#[derive(Default)]
+struct ClientHints {
+    dpr: Option<f64>,
+    width: Option<u32>,
+    viewport_width: Option<u32>,
+    save_data: bool,
+}
+
+/// Malformed hints are ignored, they are not part of the URL
+fn get_client_hints(req: &HttpRequest<AppState>) -> ClientHints {
+    if !req.state().client_hints {
+        return ClientHints::default();
+    }
+    let headers = req.headers();
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_string())
+    };
+    ClientHints {
+        dpr: header("Sec-CH-DPR")
+            .or_else(|| header("DPR"))
+            .and_then(|v| v.parse().ok()),
+        width: header("Sec-CH-Width")
+            .or_else(|| header("Width"))
+            .and_then(|v| v.parse().ok()),
+        viewport_width: header("Sec-CH-Viewport-Width")
+            .or_else(|| header("Viewport-Width"))
+            .and_then(|v| v.parse().ok()),
+        save_data: header("Save-Data").map(|v| v.eq_ignore_ascii_case("on")) == Some(true),
+    }
+}
+
+fn get_layout_size_params(req: &HttpRequest<AppState>) -> Result<(Option<u32>, Option<u32>)> {
     let params = req.match_info();
     if let Some(size_s) = params.get("size_s") {
         let re_w = Regex::new(r"w(?P<width>\d+)")?;
@@ -223,10 +420,68 @@ fn get_size_params(req: &HttpRequest<AppState>) -> Result<(Option<u32>, Option<u
     }
 }
 
-fn check_size(width: &Option<u32>, height: &Option<u32>) -> Result<()> {
+fn set_option_params(req: &HttpRequest<AppState>, img_info: &mut ImageInfo) -> Result<()> {
+    let query = req.query();
+    if let Some(meta_s) = query.get("meta") {
+        match meta_s.as_str() {
+            "keep" => img_info.metadata(false),
+            "strip" => img_info.metadata(true),
+            _ => return Err(err_msg("Illegal meta parameter")),
+        };
+    }
+    if let Some(icc_s) = query.get("icc") {
+        img_info.icc(libresizer::gen_icc_policy(icc_s)?);
+    }
+    if let Some(gamma_s) = query.get("gamma") {
+        match gamma_s.as_str() {
+            "linear" => img_info.linear(true),
+            "srgb" => img_info.linear(false),
+            _ => return Err(err_msg("Illegal gamma parameter")),
+        };
+    }
+    if let Some(depth_s) = query.get("depth") {
+        img_info.depth(libresizer::gen_depth_policy(depth_s)?);
+    }
+    if let Some(fit_s) = query.get("fit") {
+        match fit_s.as_str() {
+            "liquid" => img_info.liquid(true),
+            _ => return Err(err_msg("Illegal fit parameter")),
+        };
+    }
+    if let Some(dn_s) = query.get("dn") {
+        let sigma = match query.get("sigma") {
+            Some(sigma_s) => Some(
+                sigma_s
+                    .parse::<u32>()
+                    .map_err(|_| err_msg("Illegal sigma parameter"))?,
+            ),
+            None => None,
+        };
+        img_info.denoise(libresizer::more::denoise::gen_denoise(dn_s, sigma)?);
+    }
+    if let Some(fmt_s) = query.get("fmt") {
+        img_info.output(fmt_s);
+    }
+    Ok(())
+}
+
+/// The sizes of the resize operations stay within the limit
+pub(super) fn check_spec_sizes(spec: &TransformSpec) -> Result<()> {
+    for operation in &spec.operations {
+        match operation {
+            Operation::Resize { width, height } | Operation::Liquid { width, height } => {
+                check_size(width, height)?
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn check_size(width: &Option<u32>, height: &Option<u32>) -> Result<()> {
     let width = width.unwrap_or(0);
     let height = height.unwrap_or(0);
-    if width > 1280 || height > 1280 {
+    if width > MAX_SIZE || height > MAX_SIZE {
         Err(err_msg("Illegal size parameter"))
     } else {
         Ok(())
@@ -243,29 +498,75 @@ impl DisplayApp {
 
 impl ActixApp for DisplayApp {
     fn action(&self, state: AppState) -> App<AppState> {
-        App::with_state(state.clone())
+        let app = App::with_state(state.clone())
             .middleware(Logger::default())
-            .middleware(Logger::new("%a %{User-Agent}i"))
-            .middleware(
-                ErrorHandlers::new().handler(http::StatusCode::NOT_FOUND, render_display_404),
+            .middleware(Logger::new("%a %{User-Agent}i"));
+        let app = if state.client_hints {
+            app.middleware(
+                DefaultHeaders::new()
+                    .header("Accept-CH", CLIENT_HINTS)
+                    .header("Vary", format!("{}, Save-Data", CLIENT_HINTS)),
             )
-            .prefix("/display")
-            .resource("", |r| r.f(|_req| "Display API list"))
-            .resource("/bl/{name}.{format}", |r| r.f(display_blur))
-            .resource("/bl/{size_s}/{name}.{format}", |r| r.f(display_blur))
-            .resource("/bl{level}/{name}.{format}", |r| r.f(display_blur))
-            .resource("/bl{level}/{size_s}/{name}.{format}", |r| r.f(display_blur))
-            .resource("/cr/{crop_s}/{name}.{format}", |r| r.f(display_crop))
-            .resource("/cr/{crop_s}/bl{level}/{name}.{format}", |r| {
-                r.f(display_crop)
-            })
-            .resource("/cr/{crop_s}/bl{level}/{size_s}/{name}.{format}", |r| {
-                r.f(display_crop)
-            })
-            .resource("/cr/{crop_s}/{size_s}/{name}.{format}", |r| {
-                r.f(display_crop)
-            })
-            .resource("/{name}.{format}", |r| r.f(display_resize))
-            .resource("/{size_s}/{name}.{format}", |r| r.f(display_resize))
+        } else {
+            app
+        };
+        app.middleware(
+            ErrorHandlers::new().handler(http::StatusCode::NOT_FOUND, render_display_404),
+        )
+        .prefix("/display")
+        .resource("", |r| r.f(|_req| "Display API list"))
+        .resource("/bl/{name}.{format}", |r| r.f(display_blur))
+        .resource("/bl/{size_s}/{name}.{format}", |r| r.f(display_blur))
+        .resource("/bl{level}/{name}.{format}", |r| r.f(display_blur))
+        .resource("/bl{level}/{size_s}/{name}.{format}", |r| r.f(display_blur))
+        .resource("/cr/{crop_s}/{name}.{format}", |r| r.f(display_crop))
+        .resource("/cr/{crop_s}/bl{level}/{name}.{format}", |r| {
+            r.f(display_crop)
+        })
+        .resource("/cr/{crop_s}/bl{level}/{size_s}/{name}.{format}", |r| {
+            r.f(display_crop)
+        })
+        .resource("/cr/{crop_s}/{size_s}/{name}.{format}", |r| {
+            r.f(display_crop)
+        })
+        .resource("/cv/{kernel_s}/{name}.{format}", |r| r.f(display_convolve))
+        .resource("/cv/{kernel_s}/{size_s}/{name}.{format}", |r| {
+            r.f(display_convolve)
+        })
+        .resource("/fx/{effect_s}/{name}.{format}", |r| r.f(display_effect))
+        .resource("/fx/{effect_s}/{size_s}/{name}.{format}", |r| {
+            r.f(display_effect)
+        })
+        .resource("/lv/{levels_s}/{name}.{format}", |r| r.f(display_levels))
+        .resource("/p/{preset}/{name}.{format}", |r| r.f(display_preset))
+        .resource("/t/{ops_s}/{name}.{format}", |r| r.f(display_chain))
+        .resource("/lv/{levels_s}/{size_s}/{name}.{format}", |r| {
+            r.f(display_levels)
+        })
+        .resource("/{name}.{format}", |r| r.f(display_resize))
+        .resource("/{size_s}/{name}.{format}", |r| r.f(display_resize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hinted_width() {
+        let hints = ClientHints {
+            viewport_width: Some(1000),
+            ..ClientHints::default()
+        };
+        assert_eq!(hinted_width(&hints, 1.0, 64), Some(64));
+        assert_eq!(hinted_width(&hints, 1.0, 2000), Some(1000));
+        assert_eq!(hinted_width(&hints, 2.0, 2000), Some(1280));
+        let hints = ClientHints {
+            width: Some(1600),
+            ..hints
+        };
+        assert_eq!(hinted_width(&hints, 1.0, 64), Some(64));
+        assert_eq!(hinted_width(&hints, 1.0, 2000), Some(1280));
+        assert_eq!(hinted_width(&ClientHints::default(), 1.0, 64), None);
     }
 }
@@ -0,0 +1,105 @@
+use super::display::display;
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{fs, http, App, HttpRequest, HttpResponse};
+use libcore::errors::*;
+use libresizer::more::iiif::{self, IiifParams};
+use libresizer::ImageInfo;
+use serde_json::json;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+const CONTEXT: &str = "http://iiif.io/api/image/3/context.json";
+
+fn display_iiif(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let params = req.match_info();
+    let (name, format) = get_id_params(req).map_err(WebError::internal)?;
+    let get = |key| {
+        params
+            .get(key)
+            .ok_or(err_msg(format!("Missing {} parameter", key)))
+            .map_err(WebError::internal)
+    };
+    let iiif_params = IiifParams::new(
+        get("region")?,
+        get("size")?,
+        get("rotation")?,
+        get("quality")?,
+    )
+    .map_err(WebError::internal)?;
+    let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), None, None);
+    img_info.iiif(iiif_params);
+    img_info.output(get("format")?);
+    display(&req.state().options, &img_info)
+}
+
+fn display_info(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let id = req.match_info().get("id").unwrap_or_default().to_string();
+    let (width, height) =
+        libresizer::dimensions(&req.state().options, id.as_str()).map_err(WebError::internal)?;
+    let info = json!({
+        "@context": CONTEXT,
+        "id": service_id(req, id.as_str()),
+        "type": "ImageService3",
+        "protocol": "http://iiif.io/api/image",
+        "profile": "level2",
+        "width": width,
+        "height": height,
+        "maxWidth": iiif::MAX_SIZE,
+        "maxHeight": iiif::MAX_SIZE,
+        "extraQualities": ["color", "gray", "bitonal"],
+        "extraFormats": ["gif"],
+        "extraFeatures": ["mirroring", "sizeUpscaling"],
+    });
+    Ok(HttpResponse::Ok()
+        .content_type(format!("application/ld+json;profile=\"{}\"", CONTEXT))
+        .header("Access-Control-Allow-Origin", "*")
+        .body(info.to_string()))
+}
+
+fn redirect_info(req: &HttpRequest<AppState>) -> HttpResponse {
+    let id = req.match_info().get("id").unwrap_or_default();
+    HttpResponse::build(http::StatusCode::SEE_OTHER)
+        .header(
+            http::header::LOCATION,
+            format!("{}/info.json", service_id(req, id)),
+        )
+        .finish()
+}
+
+fn service_id(req: &HttpRequest<AppState>, id: &str) -> String {
+    let conn = req.connection_info();
+    format!("{}://{}/iiif/{}", conn.scheme(), conn.host(), id)
+}
+
+/// The IIIF identifier is the file name of the original
+fn get_id_params(req: &HttpRequest<AppState>) -> Result<(String, String)> {
+    let id = req
+        .match_info()
+        .get("id")
+        .ok_or(err_msg("Missing id parameter"))?;
+    let dot = id.rfind('.').ok_or(err_msg("Missing format in id"))?;
+    Ok((id[..dot].to_string(), id[dot + 1..].to_string()))
+}
+
+pub struct IiifApp {}
+
+impl IiifApp {
+    pub fn new() -> Self {
+        IiifApp {}
+    }
+}
+
+impl ActixApp for IiifApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/iiif")
+            .resource("/{id}", |r| r.f(redirect_info))
+            .resource("/{id}/info.json", |r| r.f(display_info))
+            .resource("/{id}/{region}/{size}/{rotation}/{quality}.{format}", |r| {
+                r.f(display_iiif)
+            })
+    }
+}
@@ -0,0 +1,180 @@
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{App, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use libcore::errors::*;
+use libresizer::more::meta;
+use libresizer::more::palette::{self, Swatch};
+use libresizer::more::phash::{self, HashIndex};
+use log::warn;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+/// Pause between two scans of the originals for the perceptual hash index
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    /// Loaded by `index_originals`, or by the first request
+    static ref Hashes: Mutex<Option<Arc<HashIndex>>> = Mutex::new(None);
+}
+
+/// Read the perceptual hash index, then keep it up to date with the originals in the
+/// background. Returns the number of originals in the index.
+pub fn index_originals(opts: &ImageOption) -> Result<usize> {
+    let hashes = hash_index(opts)?;
+    let len = hashes.len();
+    let opts = opts.clone();
+    thread::spawn(move || loop {
+        if let Err(e) = hashes.refresh(&opts) {
+            warn!("Perceptual hash index not refreshed: {}", e);
+        }
+        thread::sleep(REFRESH_INTERVAL);
+    });
+    Ok(len)
+}
+
+fn hash_index(opts: &ImageOption) -> Result<Arc<HashIndex>> {
+    let mut hashes = Hashes.lock().unwrap();
+    if hashes.is_none() {
+        *hashes = Some(Arc::new(HashIndex::load(opts.output_dir())?));
+    }
+    Ok(hashes.as_ref().unwrap().clone())
+}
+
+fn display_meta(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let fname = get_fname_params(req).map_err(WebError::internal)?;
+    let meta = meta::meta(&req.state().options, fname.as_str()).map_err(WebError::internal)?;
+    // Locations are personal data, they are only returned on request
+    let with_gps = req.query().get("gps").map(|g| g == "1" || g == "true") == Some(true);
+    let exif = meta.exif.map(|exif| {
+        let camera = match (&exif.make, &exif.model) {
+            (Some(make), Some(model)) if model.starts_with(make.as_str()) => Some(model.clone()),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.clone().or_else(|| model.clone()),
+        };
+        let mut exif_json = json!({
+            "camera": camera,
+            "make": exif.make,
+            "model": exif.model,
+            "date": exif.date_time,
+            "orientation": exif.orientation,
+        });
+        if with_gps {
+            exif_json["gps"] = json!(exif.gps.map(|(latitude, longitude)| json!({
+                "latitude": latitude,
+                "longitude": longitude,
+            })));
+        }
+        exif_json
+    });
+    let body = json!({
+        "name": fname,
+        "width": meta.width,
+        "height": meta.height,
+        "format": meta.format,
+        "color_type": meta.color_type,
+        "bit_depth": meta.bit_depth,
+        "file_size": meta.file_size,
+        "frames": meta.frames,
+        "exif": exif,
+        "hash": format!("sha256:{}", meta.hash),
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+fn display_palette(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let fname = get_fname_params(req).map_err(WebError::internal)?;
+    let colors: usize = req
+        .query()
+        .get("k")
+        .map(|k| k.as_str())
+        .unwrap_or("5")
+        .parse()
+        .map_err(WebError::parse)?;
+    let swatches = palette::palette(&req.state().options, fname.as_str(), colors)
+        .map_err(WebError::internal)?;
+    let body = json!({
+        "dominant": swatches.first().map(swatch_json),
+        "palette": swatches.iter().map(swatch_json).collect::<Vec<Value>>(),
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+fn display_duplicates(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let fname = get_fname_params(req).map_err(WebError::internal)?;
+    let query = req.query();
+    let kind = phash::gen_hash_kind(query.get("hash").map(|h| h.as_str()).unwrap_or("phash"))
+        .map_err(WebError::internal)?;
+    let threshold: u32 = query
+        .get("distance")
+        .map(|d| d.as_str())
+        .unwrap_or("10")
+        .parse()
+        .map_err(WebError::parse)?;
+    let opts = &req.state().options;
+    let (hash, duplicates) = hash_index(opts)
+        .and_then(|hashes| hashes.duplicates(opts, fname.as_str(), kind, threshold))
+        .map_err(WebError::internal)?;
+    let body = json!({
+        "name": fname,
+        "hash": {
+            "ahash": format!("{:016x}", hash.ahash),
+            "dhash": format!("{:016x}", hash.dhash),
+            "phash": format!("{:016x}", hash.phash),
+        },
+        "duplicates": duplicates.iter().map(|(name, distance)| json!({
+            "name": name,
+            "distance": distance,
+        })).collect::<Vec<Value>>(),
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+fn swatch_json(swatch: &Swatch) -> Value {
+    json!({
+        "hex": swatch.hex(),
+        "rgb": swatch.rgb,
+        "population": swatch.population,
+    })
+}
+
+fn get_fname_params(req: &HttpRequest<AppState>) -> Result<String> {
+    let params = req.match_info();
+    let name = params
+        .get("name")
+        .ok_or(err_msg("Missing name parameter"))?;
+    let format = params
+        .get("format")
+        .ok_or(err_msg("Missing format parameter"))?;
+    Ok(format!("{}.{}", name, format))
+}
+
+pub struct MetaApp {}
+
+impl MetaApp {
+    pub fn new() -> Self {
+        MetaApp {}
+    }
+}
+
+impl ActixApp for MetaApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/meta")
+            .resource("/{name}.{format}", |r| r.f(display_meta))
+            .resource("/{name}.{format}/palette", |r| r.f(display_palette))
+            .resource("/{name}.{format}/duplicates", |r| r.f(display_duplicates))
+    }
+}
@@ -1,23 +1,100 @@
-use actix_web::App;
-use libresizer::ImageOption;
+use actix_web::{server, App};
+use libresizer::{ImageOption, TransformSpec};
+use log::{info, warn};
+use std::collections::HashMap;
 
+mod compose;
 mod display;
+mod iiif;
 mod index;
+mod meta;
+mod placeholder;
+mod sprite;
+mod srcset;
+mod tiles;
+mod transform;
 
-pub use self::display::DisplayApp;
+pub use self::compose::ComposeApp;
+pub use self::display::{load_cache, DisplayApp};
+pub use self::iiif::IiifApp;
 pub use self::index::IndexApp;
+pub use self::meta::{index_originals, MetaApp};
+pub use self::placeholder::PlaceholderApp;
+pub use self::sprite::SpriteApp;
+pub use self::srcset::{parse_breakpoints, SrcsetApp, DEFAULT_PRESET};
+pub use self::tiles::TilesApp;
+pub use self::transform::TransformApp;
 
 #[derive(Clone)]
 pub struct AppState {
     pub options: ImageOption,
+    /// Named lists of widths for the srcset manifests
+    pub breakpoints: HashMap<String, Vec<u32>>,
+    /// Honour the Client Hints request headers in `/display`
+    pub client_hints: bool,
+    /// Named operation lists of `/display/p`
+    pub presets: HashMap<String, TransformSpec>,
 }
 
 impl AppState {
     pub fn new(options: ImageOption) -> Self {
-        AppState { options }
+        let mut breakpoints = HashMap::new();
+        breakpoints.insert(DEFAULT_PRESET.to_string(), vec![320, 640, 960, 1280]);
+        AppState {
+            options,
+            breakpoints,
+            client_hints: false,
+            presets: HashMap::new(),
+        }
+    }
+
+    pub fn breakpoints(&mut self, name: &str, widths: Vec<u32>) -> &mut Self {
+        self.breakpoints.insert(name.to_string(), widths);
+        self
+    }
+
+    pub fn client_hints(&mut self, enabled: bool) -> &mut Self {
+        self.client_hints = enabled;
+        self
+    }
+
+    pub fn presets(&mut self, presets: HashMap<String, TransformSpec>) -> &mut Self {
+        self.presets = presets;
+        self
     }
 }
 
 pub trait ActixApp {
     fn action(&self, state: AppState) -> App<AppState>;
 }
+
+/// Start the server with every app. Downstream crates register their handlers on the options
+/// of `state` before calling it, see `libresizer::HandlerRegistry`.
+pub fn serve(state: AppState, bind_s: &str) {
+    match load_cache(&state.options) {
+        Ok(len) => info!("{} outputs in the cache", len),
+        Err(e) => warn!("Cache index not loaded: {}", e),
+    }
+    match index_originals(&state.options) {
+        Ok(len) => info!("{} originals in the perceptual hash index", len),
+        Err(e) => warn!("Perceptual hash index not loaded: {}", e),
+    }
+    let apps = move || {
+        vec![
+            DisplayApp::new().action(state.clone()).finish(),
+            TilesApp::new().action(state.clone()).finish(),
+            IiifApp::new().action(state.clone()).finish(),
+            SrcsetApp::new().action(state.clone()).finish(),
+            PlaceholderApp::new().action(state.clone()).finish(),
+            MetaApp::new().action(state.clone()).finish(),
+            ComposeApp::new().action(state.clone()).finish(),
+            SpriteApp::new().action(state.clone()).finish(),
+            TransformApp::new().action(state.clone()).finish(),
+            IndexApp::new().action(state.clone()).finish(),
+        ]
+    };
+
+    info!("Starting app……");
+    info!("http://{}", bind_s);
+    server::new(apps).bind(bind_s).unwrap().run();
+}
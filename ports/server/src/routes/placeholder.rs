@@ -0,0 +1,73 @@
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{App, HttpRequest, HttpResponse};
+use libcore::errors::*;
+use libresizer::more::placeholder;
+use serde_json::json;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+fn display_placeholder(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let params = req.match_info();
+    let query = req.query();
+    let name = params
+        .get("name")
+        .ok_or(err_msg("Missing name parameter"))
+        .map_err(WebError::internal)?;
+    let format = params
+        .get("format")
+        .ok_or(err_msg("Missing format parameter"))
+        .map_err(WebError::internal)?;
+    // The 4x3 components recommended by BlurHash
+    let x: u32 = query
+        .get("x")
+        .map(|x| x.as_str())
+        .unwrap_or("4")
+        .parse()
+        .map_err(|_| invalid_param("Illegal x parameter"))
+        .map_err(WebError::internal)?;
+    let y: u32 = query
+        .get("y")
+        .map(|y| y.as_str())
+        .unwrap_or("3")
+        .parse()
+        .map_err(|_| invalid_param("Illegal y parameter"))
+        .map_err(WebError::internal)?;
+    let placeholder = placeholder::placeholder(
+        &req.state().options,
+        format!("{}.{}", name, format).as_str(),
+        x,
+        y,
+    )
+    .map_err(WebError::internal)?;
+    let mut body = json!({
+        "width": placeholder.width,
+        "height": placeholder.height,
+        "blurhash": placeholder.blurhash,
+        "thumbhash": placeholder.thumbhash,
+    });
+    if query.get("lqip").map(|l| l == "1" || l == "true") == Some(true) {
+        body["lqip"] = json!(placeholder.lqip);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+pub struct PlaceholderApp {}
+
+impl PlaceholderApp {
+    pub fn new() -> Self {
+        PlaceholderApp {}
+    }
+}
+
+impl ActixApp for PlaceholderApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/placeholder")
+            .resource("/{name}.{format}", |r| r.f(display_placeholder))
+    }
+}
@@ -0,0 +1,150 @@
+use super::compose::get_images_params;
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{fs, App, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use libcore::errors::*;
+use libresizer::more::effects::gen_color;
+use libresizer::more::sprite::{self, SheetOption, SpriteSheet};
+use serde_json::json;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+const DEFAULT_CELL_SIZE: u32 = 64;
+
+lazy_static! {
+    static ref Sheets: Mutex<HashMap<u64, SpriteSheet>> = Mutex::new(HashMap::new());
+}
+
+fn sprite_image(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let format = req
+        .match_info()
+        .get("format")
+        .ok_or(err_msg("Missing format parameter"))
+        .map_err(WebError::internal)?;
+    let sheet = get_sheet(req, format)?;
+    let mut opath = PathBuf::from(&req.state().options.output_dir());
+    opath.push(sheet.hash.to_string());
+    opath.set_extension(format);
+    Ok(fs::NamedFile::open(
+        opath
+            .to_str()
+            .ok_or(err_msg("No output file found"))
+            .map_err(WebError::internal)?,
+    )
+    .map_err(WebError::io)?)
+}
+
+fn sprite_json(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let format = get_format_params(req);
+    let sheet = get_sheet(req, &format)?;
+    let sprites: Vec<_> = sheet
+        .sprites
+        .iter()
+        .map(|s| {
+            json!({
+                "name": s.fname,
+                "x": s.x,
+                "y": s.y,
+                "width": s.width,
+                "height": s.height,
+            })
+        })
+        .collect();
+    let body = json!({
+        "image": image_url(req, &format),
+        "width": sheet.width,
+        "height": sheet.height,
+        "sprites": sprites,
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body.to_string()))
+}
+
+fn sprite_css(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let format = get_format_params(req);
+    let sheet = get_sheet(req, &format)?;
+    let prefix = req
+        .query()
+        .get("prefix")
+        .cloned()
+        .unwrap_or_else(|| "sprite".to_string());
+    Ok(HttpResponse::Ok()
+        .content_type("text/css")
+        .body(sheet.css(&image_url(req, &format), &prefix)))
+}
+
+/// Build the sheet on the first request, the image, JSON and CSS share it
+fn get_sheet(req: &HttpRequest<AppState>, format: &str) -> WebResult<SpriteSheet> {
+    let query = req.query();
+    let fnames = get_images_params(req).map_err(WebError::internal)?;
+    let number = |key: &str| -> WebResult<Option<u32>> {
+        match query.get(key) {
+            Some(value_s) => Ok(Some(value_s.parse().map_err(WebError::parse)?)),
+            None => Ok(None),
+        }
+    };
+    let cell_width = number("w")?.unwrap_or(DEFAULT_CELL_SIZE);
+    let sheet_opts = SheetOption {
+        cell_width,
+        cell_height: number("h")?.unwrap_or(cell_width),
+        columns: number("cols")?,
+        padding: number("padding")?.unwrap_or(0),
+        // Transparent unless it is a contact sheet
+        background: match query.get("bg") {
+            Some(bg_s) => gen_color(bg_s).map_err(WebError::internal)?,
+            None => [0, 0, 0, 0],
+        },
+    };
+    let mut hasher = DefaultHasher::new();
+    fnames.hash(&mut hasher);
+    sheet_opts.hash(&mut hasher);
+    format.hash(&mut hasher);
+    let sheet_hash = hasher.finish();
+    let mut sheets = Sheets.lock().unwrap();
+    if let Some(sheet) = sheets.get(&sheet_hash) {
+        return Ok(sheet.clone());
+    }
+    let sheet = sprite::sprite(&req.state().options, &fnames, &sheet_opts, format)
+        .map_err(WebError::internal)?;
+    sheets.insert(sheet_hash, sheet.clone());
+    Ok(sheet)
+}
+
+/// The image format of the sheet described by the JSON and CSS, `fmt=png` by default
+fn get_format_params(req: &HttpRequest<AppState>) -> String {
+    req.query()
+        .get("fmt")
+        .cloned()
+        .unwrap_or_else(|| "png".to_string())
+}
+
+fn image_url(req: &HttpRequest<AppState>, format: &str) -> String {
+    format!("/sprite/sheet.{}?{}", format, req.query_string())
+}
+
+pub struct SpriteApp {}
+
+impl SpriteApp {
+    pub fn new() -> Self {
+        SpriteApp {}
+    }
+}
+
+impl ActixApp for SpriteApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/sprite")
+            .resource("/sheet.json", |r| r.f(sprite_json))
+            .resource("/sheet.css", |r| r.f(sprite_css))
+            .resource("/sheet.{format}", |r| r.f(sprite_image))
+    }
+}
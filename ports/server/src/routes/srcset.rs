@@ -0,0 +1,182 @@
+use super::display::{check_size, display, get_file_params};
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{App, HttpRequest, HttpResponse};
+use libcore::errors::*;
+use libresizer::ImageInfo;
+use serde_json::json;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+/// The breakpoint preset used when a request names none
+pub const DEFAULT_PRESET: &str = "default";
+
+/// Parse a `--breakpoints` value, `name=320,640,960` or `320,640,960` for the default preset
+pub fn parse_breakpoints(spec: &str) -> Result<(String, Vec<u32>)> {
+    let (name, widths_s) = match spec.find('=') {
+        Some(i) => (&spec[..i], &spec[i + 1..]),
+        None => (DEFAULT_PRESET, spec),
+    };
+    Ok((name.to_string(), parse_widths(widths_s)?))
+}
+
+fn parse_widths(widths_s: &str) -> Result<Vec<u32>> {
+    let mut widths = widths_s
+        .split(',')
+        .map(|w| w.trim().parse::<u32>())
+        .collect::<std::result::Result<Vec<u32>, _>>()
+        .map_err(|_| invalid_param(format!("Illegal widths: {}", widths_s)))?;
+    if widths.is_empty() || widths.contains(&0) {
+        return Err(invalid_param(format!("Illegal widths: {}", widths_s)));
+    }
+    widths.sort();
+    widths.dedup();
+    Ok(widths)
+}
+
+fn display_manifest(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let state = req.state();
+    let query = req.query();
+    let (name, format) = get_file_params(req).map_err(WebError::internal)?;
+    let fname = format!("{}.{}", name, format);
+    let widths = if let Some(widths_s) = query.get("widths") {
+        parse_widths(widths_s).map_err(WebError::internal)?
+    } else {
+        let preset = query
+            .get("preset")
+            .map(|p| p.as_str())
+            .unwrap_or(DEFAULT_PRESET);
+        state
+            .breakpoints
+            .get(preset)
+            .cloned()
+            .ok_or(invalid_param(format!(
+                "Unknown breakpoint preset: {}",
+                preset
+            )))
+            .map_err(WebError::internal)?
+    };
+    // The last format is the fallback of the <img> element
+    let formats: Vec<String> = query
+        .get("formats")
+        .map(|f| f.split(',').map(|f| f.trim().to_lowercase()).collect())
+        .unwrap_or_else(|| vec![format.to_lowercase()]);
+    let sizes = query.get("sizes").map(|s| s.as_str()).unwrap_or("100vw");
+    let (width, height) =
+        libresizer::dimensions(&state.options, fname.as_str()).map_err(WebError::internal)?;
+    // Upscaled candidates only cost bandwidth, the original width stands in for them
+    let mut widths: Vec<u32> = widths
+        .into_iter()
+        .map(|w| w.min(width))
+        .filter(|w| check_size(&Some(*w), &None).is_ok())
+        .collect();
+    widths.dedup();
+    if widths.is_empty() {
+        return Err(WebError::internal(invalid_param("No usable widths")));
+    }
+
+    let mut sources = Vec::new();
+    for fmt in &formats {
+        let mime = libresizer::mime_type(fmt)
+            .map_err(|_| invalid_param(format!("Unsupported format: {}", fmt)))
+            .map_err(WebError::internal)?;
+        let candidates: Vec<(u32, String)> = widths
+            .iter()
+            .map(|w| (*w, display_url(&name, &format, *w, fmt)))
+            .collect();
+        if query.get("pregen").map(|p| p == "1" || p == "true") == Some(true) {
+            for (w, _) in &candidates {
+                let mut img_info = ImageInfo::new(name.as_str(), format.as_str(), Some(*w), None);
+                if fmt != &format {
+                    img_info.output(fmt);
+                }
+                display(&state.options, &img_info)?;
+            }
+        }
+        sources.push((fmt.clone(), mime, candidates));
+    }
+
+    let srcset = |candidates: &Vec<(u32, String)>| {
+        candidates
+            .iter()
+            .map(|(w, url)| format!("{} {}w", url, w))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let (_, fallback_mime, fallback) = sources.last().unwrap();
+    let fallback_src = fallback.last().unwrap().1.clone();
+    let mut html = String::from("<picture>\n");
+    for (_, mime, candidates) in &sources[..sources.len() - 1] {
+        html.push_str(&format!(
+            "  <source type=\"{}\" srcset=\"{}\" sizes=\"{}\">\n",
+            mime,
+            escape(&srcset(candidates)),
+            escape(sizes)
+        ));
+    }
+    html.push_str(&format!(
+        "  <img src=\"{}\" srcset=\"{}\" sizes=\"{}\" width=\"{}\" height=\"{}\" alt=\"\">\n</picture>",
+        escape(&fallback_src),
+        escape(&srcset(fallback)),
+        escape(sizes),
+        width,
+        height
+    ));
+
+    let manifest = json!({
+        "name": fname,
+        "width": width,
+        "height": height,
+        "sizes": sizes,
+        "src": fallback_src,
+        "type": fallback_mime,
+        "sources": sources.iter().map(|(fmt, mime, candidates)| json!({
+            "format": fmt,
+            "type": mime,
+            "srcset": srcset(candidates),
+            "candidates": candidates.iter().map(|(w, url)| json!({
+                "width": w,
+                "url": url,
+            })).collect::<Vec<_>>(),
+        })).collect::<Vec<_>>(),
+        "html": html,
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(manifest.to_string()))
+}
+
+fn display_url(name: &str, format: &str, width: u32, fmt: &str) -> String {
+    if fmt == format {
+        format!("/display/w{}/{}.{}", width, name, format)
+    } else {
+        format!("/display/w{}/{}.{}?fmt={}", width, name, format, fmt)
+    }
+}
+
+/// Escape a value for a double-quoted HTML attribute
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+pub struct SrcsetApp {}
+
+impl SrcsetApp {
+    pub fn new() -> Self {
+        SrcsetApp {}
+    }
+}
+
+impl ActixApp for SrcsetApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/srcset")
+            .resource("/{name}.{format}", |r| r.f(display_manifest))
+    }
+}
@@ -0,0 +1,120 @@
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{fs, App, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use libcore::errors::*;
+use libresizer::more::tile;
+use std::sync::{Arc, Mutex};
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+lazy_static! {
+    // Concurrent requests for a new original would otherwise build the same pyramid, one lock
+    // per pyramid being generated
+    static ref Generation: Mutex<HashMap<String, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+fn display_tile(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let params = req.match_info();
+    let (name, format) = get_tile_params(req).map_err(WebError::internal)?;
+    let level: u32 = params
+        .get("z")
+        .unwrap_or("0")
+        .parse()
+        .map_err(WebError::parse)?;
+    let col: u32 = params
+        .get("x")
+        .unwrap_or("0")
+        .parse()
+        .map_err(WebError::parse)?;
+    let row: u32 = params
+        .get("y")
+        .unwrap_or("0")
+        .parse()
+        .map_err(WebError::parse)?;
+    let opts = &req.state().options;
+    let tpath = with_pyramid(opts, &name, &format, || {
+        tile::tile(opts, name.as_str(), format.as_str(), level, col, row)
+    })
+    .map_err(WebError::internal)?;
+    fs::NamedFile::open(tpath).map_err(WebError::io)
+}
+
+fn display_descriptor(req: &HttpRequest<AppState>) -> WebResult<HttpResponse> {
+    let (name, format) = get_tile_params(req).map_err(WebError::internal)?;
+    let opts = &req.state().options;
+    let dzi = with_pyramid(opts, &name, &format, || {
+        tile::descriptor(opts, name.as_str(), format.as_str())
+    })
+    .map_err(WebError::internal)?;
+    Ok(HttpResponse::Ok().content_type("application/xml").body(dzi))
+}
+
+/// Run `f` right away when the pyramid exists, otherwise once the other requests generating
+/// the same pyramid are done
+fn with_pyramid<T, F>(opts: &ImageOption, name: &str, format: &str, f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T>,
+{
+    if tile::generated(opts, name, format) {
+        return f();
+    }
+    let key = format!("{}/{}", name, format);
+    let lock = Generation
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_insert_with(|| Arc::new(Mutex::new(())))
+        .clone();
+    let _generation = lock.lock().unwrap();
+    let result = f();
+    // A later generation may have replaced the entry already, it is left to its own requests
+    let mut generation = Generation.lock().unwrap();
+    if generation
+        .get(&key)
+        .map_or(false, |entry| Arc::ptr_eq(entry, &lock))
+    {
+        generation.remove(&key);
+    }
+    result
+}
+
+/// The original file name and the tile format, which defaults to the format of the original
+fn get_tile_params(req: &HttpRequest<AppState>) -> Result<(String, String)> {
+    let params = req.match_info();
+    let name = params
+        .get("name")
+        .ok_or(err_msg("Missing name parameter"))?;
+    let format = if let Some(format) = params.get("format") {
+        format.to_string()
+    } else if let Some(format) = req.query().get("format") {
+        format.to_string()
+    } else {
+        name.rsplit('.')
+            .next()
+            .ok_or(err_msg("Missing format parameter"))?
+            .to_string()
+    };
+    Ok((name.to_string(), format))
+}
+
+pub struct TilesApp {}
+
+impl TilesApp {
+    pub fn new() -> Self {
+        TilesApp {}
+    }
+}
+
+impl ActixApp for TilesApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/tiles")
+            .resource("/{name}.dzi", |r| r.f(display_descriptor))
+            // Where Deep Zoom viewers look for the tiles of `{name}.dzi`
+            .resource("/{name}_files/{z}/{x}_{y}.{format}", |r| r.f(display_tile))
+            .resource("/{name}/{z}/{x}_{y}.{format}", |r| r.f(display_tile))
+    }
+}
@@ -0,0 +1,157 @@
+use super::display::{check_spec_sizes, process};
+use super::*;
+use crate::errors::*;
+use actix_web::middleware::Logger;
+use actix_web::{fs, http, App, HttpRequest, HttpResponse, Json};
+use libcore::errors::*;
+use libresizer::more::compose::check_fname;
+use libresizer::{ImageInfo, TransformSpec};
+use serde_derive::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+type WebResult<T> = std::result::Result<T, WebError>;
+
+/// Uploads are base64 encoded in the body
+const MAX_BODY: usize = 16 * 1024 * 1024;
+/// Uploads are decoded in memory, larger ones are refused before decoding
+const MAX_PIXELS: u64 = 50_000_000;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply {
+    /// The output itself
+    Image,
+    /// A JSON reference to the output, served by `/transform/{hash}.{format}`
+    Url,
+}
+
+impl Default for Reply {
+    fn default() -> Self {
+        Reply::Image
+    }
+}
+
+/// The body of `POST /transform`: a source and the fields of a `TransformSpec`
+#[derive(Debug, Deserialize)]
+struct TransformRequest {
+    /// File name of an original
+    source: Option<String>,
+    /// Base64 encoded image, instead of an original
+    data: Option<String>,
+    #[serde(flatten)]
+    spec: TransformSpec,
+    #[serde(default)]
+    reply: Reply,
+}
+
+fn transform(
+    (req, body): (HttpRequest<AppState>, Json<TransformRequest>),
+) -> WebResult<HttpResponse> {
+    let opts = &req.state().options;
+    let body = body.into_inner();
+    body.spec
+        .check(opts.handler_registry())
+        .map_err(WebError::internal)?;
+    check_spec_sizes(&body.spec).map_err(WebError::internal)?;
+    let (mut img_info, data) = match (&body.source, &body.data) {
+        (Some(source), None) => {
+            check_fname(source).map_err(WebError::internal)?;
+            let mut parts = source.rsplitn(2, '.');
+            let format = parts.next().unwrap_or_default();
+            let name = parts
+                .next()
+                .ok_or(invalid_param(format!("Illegal image name: {}", source)))
+                .map_err(WebError::internal)?;
+            (ImageInfo::new(name, format, None, None), None)
+        }
+        (None, Some(data_s)) => {
+            let data = base64::decode(data_s)
+                .map_err(|_| invalid_param("Illegal base64 data"))
+                .map_err(WebError::internal)?;
+            let format = libresizer::data_format(&data).map_err(WebError::internal)?;
+            let (width, height) = libresizer::data_dimensions(&data).map_err(WebError::internal)?;
+            if u64::from(width) * u64::from(height) > MAX_PIXELS {
+                return Err(WebError::internal(invalid_param(format!(
+                    "Images of more than {} pixels are refused",
+                    MAX_PIXELS
+                ))));
+            }
+            // Named after the content, the same upload hits the cache
+            let name = format!("upload-{:x}", Sha256::digest(&data));
+            (ImageInfo::new(&name, format, None, None), Some(data))
+        }
+        _ => {
+            return Err(WebError::internal(invalid_param(
+                "Either source or data is required",
+            )))
+        }
+    };
+    img_info.transform(body.spec);
+    let format = img_info.output_format().to_string();
+    let mime = libresizer::mime_type(&format).map_err(|e| WebError::BadRequest(e.to_string()))?;
+    let hash = process(opts, &img_info, data.as_ref().map(|data| data.as_slice()))?;
+    match body.reply {
+        Reply::Url => {
+            let body = json!({
+                "url": format!("/transform/{}.{}", hash, format),
+                "format": format,
+            });
+            Ok(HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body.to_string()))
+        }
+        Reply::Image => {
+            let mut opath = PathBuf::from(&opts.output_dir());
+            opath.push(hash.to_string());
+            opath.set_extension(&format);
+            let encoded = std::fs::read(&opath).map_err(WebError::io)?;
+            Ok(HttpResponse::Ok().content_type(mime).body(encoded))
+        }
+    }
+}
+
+fn transform_output(req: &HttpRequest<AppState>) -> WebResult<fs::NamedFile> {
+    let params = req.match_info();
+    let hash: u64 = params
+        .get("hash")
+        .unwrap_or_default()
+        .parse()
+        .map_err(WebError::parse)?;
+    let format = params.get("format").unwrap_or_default();
+    // Only image outputs are served
+    libresizer::mime_type(format).map_err(|_| WebError::NotFound)?;
+    let mut opath = PathBuf::from(&req.state().options.output_dir());
+    opath.push(hash.to_string());
+    opath.set_extension(format);
+    Ok(fs::NamedFile::open(
+        opath
+            .to_str()
+            .ok_or(err_msg("No output file found"))
+            .map_err(WebError::internal)?,
+    )
+    .map_err(WebError::io)?)
+}
+
+pub struct TransformApp {}
+
+impl TransformApp {
+    pub fn new() -> Self {
+        TransformApp {}
+    }
+}
+
+impl ActixApp for TransformApp {
+    fn action(&self, state: AppState) -> App<AppState> {
+        App::with_state(state.clone())
+            .middleware(Logger::default())
+            .prefix("/transform")
+            .resource("", |r| {
+                r.method(http::Method::POST).with_config(transform, |cfg| {
+                    cfg.1.limit(MAX_BODY);
+                })
+            })
+            .resource("/{hash}.{format}", |r| r.f(transform_output))
+    }
+}